@@ -0,0 +1,356 @@
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Limits and timings applied to every websocket connection
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+    /// Take the client IP from `X-Forwarded-For`. Only safe behind a proxy
+    /// that sets the header, such as the ingress.
+    pub trust_forwarded_for: bool,
+}
+
+/// IP a client connects from. Behind a trusted proxy that is the last entry of
+/// `X-Forwarded-For`, the one the proxy appended itself; earlier entries come
+/// from the client and can't be trusted.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    if !trust_forwarded_for {
+        return peer.ip();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or_else(|| peer.ip())
+}
+
+/// Why a new connection was refused
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    ServerFull(usize),
+    TooManyFromIp(usize),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ServerFull(max) => write!(f, "server connection limit reached ({})", max),
+            Rejection::TooManyFromIp(max) => {
+                write!(f, "per-IP connection limit reached ({})", max)
+            }
+        }
+    }
+}
+
+/// Live statistics for one websocket session
+#[derive(Debug)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    /// IP connection limits are counted against
+    pub client_ip: IpAddr,
+    connected_at: SystemTime,
+    started: Instant,
+    subscriptions: Mutex<Vec<String>>,
+    // Preferred quote tokens for oriented prices, set by the client
+    quote_tokens: Mutex<Option<Vec<String>>>,
+    last_sent_block: AtomicU64,
+    coalesced_updates: AtomicU64,
+    last_pong_ms: AtomicU64,
+    awaiting_pong: AtomicBool,
+}
+
+impl SessionInfo {
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn subscribe(&self, topic: &str) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if !subscriptions.iter().any(|t| t == topic) {
+            subscriptions.push(topic.to_string());
+        }
+    }
+
+    pub fn unsubscribe(&self, topic: &str) {
+        self.subscriptions.lock().unwrap().retain(|t| t != topic);
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions.lock().unwrap().iter().any(|t| t == topic)
+    }
+
+    pub fn set_quote_tokens(&self, tokens: Vec<String>) {
+        let tokens = tokens.into_iter().map(|token| token.to_lowercase()).collect();
+        *self.quote_tokens.lock().unwrap() = Some(tokens);
+    }
+
+    pub fn quote_tokens(&self) -> Option<Vec<String>> {
+        self.quote_tokens.lock().unwrap().clone()
+    }
+
+    pub fn record_sent(&self, block_number: u64) {
+        self.last_sent_block.store(block_number, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self, count: u64) {
+        self.coalesced_updates.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_ping(&self) {
+        self.awaiting_pong.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record_pong(&self) {
+        self.last_pong_ms.store(self.elapsed_ms(), Ordering::Relaxed);
+        self.awaiting_pong.store(false, Ordering::Relaxed);
+    }
+
+    pub fn awaiting_pong(&self) -> bool {
+        self.awaiting_pong.load(Ordering::Relaxed)
+    }
+
+    fn summary(&self, current_block: u64) -> SessionSummary {
+        let last_sent_block = self.last_sent_block.load(Ordering::Relaxed);
+        let last_pong_ms = self.last_pong_ms.load(Ordering::Relaxed);
+        SessionSummary {
+            id: self.id,
+            remote_addr: self.remote_addr.to_string(),
+            client_ip: self.client_ip.to_string(),
+            connected_at: self
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            subscriptions: self.subscriptions.lock().unwrap().clone(),
+            quote_tokens: self.quote_tokens(),
+            last_sent_block,
+            lag_blocks: current_block.saturating_sub(last_sent_block),
+            coalesced_updates: self.coalesced_updates.load(Ordering::Relaxed),
+            secs_since_last_pong: self.elapsed_ms().saturating_sub(last_pong_ms) / 1000,
+            awaiting_pong: self.awaiting_pong(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionSummary {
+    pub id: u64,
+    pub remote_addr: String,
+    pub client_ip: String,
+    pub connected_at: u64,
+    pub subscriptions: Vec<String>,
+    pub quote_tokens: Option<Vec<String>>,
+    pub last_sent_block: u64,
+    pub lag_blocks: u64,
+    pub coalesced_updates: u64,
+    pub secs_since_last_pong: u64,
+    pub awaiting_pong: bool,
+}
+
+#[derive(Debug, Default)]
+struct RegistryInner {
+    next_id: u64,
+    sessions: HashMap<u64, Arc<SessionInfo>>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Tracks open websocket sessions and enforces connection limits
+#[derive(Debug, Clone)]
+pub struct ConnectionRegistry {
+    limits: ConnectionLimits,
+    inner: Arc<Mutex<RegistryInner>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionRegistry {
+            limits,
+            inner: Arc::new(Mutex::new(RegistryInner::default())),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Register a new session, or refuse it if a limit would be exceeded.
+    /// The session is unregistered when the returned guard is dropped.
+    pub fn register(
+        &self,
+        remote_addr: SocketAddr,
+        client_ip: IpAddr,
+    ) -> Result<SessionGuard, Rejection> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sessions.len() >= self.limits.max_connections {
+            return Err(Rejection::ServerFull(self.limits.max_connections));
+        }
+        let from_ip = inner.per_ip.get(&client_ip).copied().unwrap_or(0);
+        if from_ip >= self.limits.max_connections_per_ip {
+            return Err(Rejection::TooManyFromIp(self.limits.max_connections_per_ip));
+        }
+
+        inner.next_id += 1;
+        let session = Arc::new(SessionInfo {
+            id: inner.next_id,
+            remote_addr,
+            client_ip,
+            connected_at: SystemTime::now(),
+            started: Instant::now(),
+            subscriptions: Mutex::new(Vec::new()),
+            quote_tokens: Mutex::new(None),
+            last_sent_block: AtomicU64::new(0),
+            coalesced_updates: AtomicU64::new(0),
+            last_pong_ms: AtomicU64::new(0),
+            awaiting_pong: AtomicBool::new(false),
+        });
+        inner.sessions.insert(session.id, session.clone());
+        *inner.per_ip.entry(client_ip).or_insert(0) += 1;
+
+        Ok(SessionGuard {
+            registry: self.clone(),
+            session,
+        })
+    }
+
+    fn unregister(&self, session: &SessionInfo) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sessions.remove(&session.id).is_none() {
+            return;
+        }
+        if let Some(count) = inner.per_ip.get_mut(&session.client_ip) {
+            *count -= 1;
+            if *count == 0 {
+                inner.per_ip.remove(&session.client_ip);
+            }
+        }
+    }
+
+    pub fn list(&self, current_block: u64) -> Vec<SessionSummary> {
+        let inner = self.inner.lock().unwrap();
+        let mut sessions: Vec<SessionSummary> = inner
+            .sessions
+            .values()
+            .map(|s| s.summary(current_block))
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+}
+
+/// Keeps a session registered for as long as it is alive
+pub struct SessionGuard {
+    registry: ConnectionRegistry,
+    session: Arc<SessionInfo>,
+}
+
+impl SessionGuard {
+    pub fn info(&self) -> Arc<SessionInfo> {
+        self.session.clone()
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn registry(max_connections: usize, max_connections_per_ip: usize) -> ConnectionRegistry {
+        ConnectionRegistry::new(ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            trust_forwarded_for: false,
+        })
+    }
+
+    fn addr(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), port)
+    }
+
+    fn connect(registry: &ConnectionRegistry, ip: &str, port: u16) -> Result<SessionGuard, Rejection> {
+        let remote_addr = addr(ip, port);
+        registry.register(remote_addr, remote_addr.ip())
+    }
+
+    #[test]
+    fn refuses_connections_past_the_server_limit() {
+        let registry = registry(2, 10);
+        let _first = connect(&registry, "10.0.0.1", 1).unwrap();
+        let second = connect(&registry, "10.0.0.2", 1).unwrap();
+        assert!(matches!(
+            connect(&registry, "10.0.0.3", 1),
+            Err(Rejection::ServerFull(2))
+        ));
+
+        // Dropping a guard frees its slot
+        drop(second);
+        assert!(connect(&registry, "10.0.0.3", 1).is_ok());
+    }
+
+    #[test]
+    fn refuses_connections_past_the_per_ip_limit() {
+        let registry = registry(10, 2);
+        let first = connect(&registry, "10.0.0.1", 1).unwrap();
+        let _second = connect(&registry, "10.0.0.1", 2).unwrap();
+        assert!(matches!(
+            connect(&registry, "10.0.0.1", 3),
+            Err(Rejection::TooManyFromIp(2))
+        ));
+        // Other IPs are unaffected
+        assert!(connect(&registry, "10.0.0.2", 1).is_ok());
+
+        drop(first);
+        assert!(connect(&registry, "10.0.0.1", 3).is_ok());
+    }
+
+    #[test]
+    fn counts_per_ip_limits_against_the_client_ip() {
+        let registry = registry(10, 1);
+        let proxy = addr("10.0.0.1", 1);
+        let _first = registry.register(proxy, "1.1.1.1".parse().unwrap()).unwrap();
+        // Another client behind the same proxy isn't refused
+        assert!(registry.register(addr("10.0.0.1", 2), "2.2.2.2".parse().unwrap()).is_ok());
+        assert!(matches!(
+            registry.register(addr("10.0.0.1", 3), "1.1.1.1".parse().unwrap()),
+            Err(Rejection::TooManyFromIp(1))
+        ));
+    }
+
+    #[test]
+    fn takes_the_client_ip_from_the_last_forwarded_entry_when_trusted() {
+        let peer = addr("10.0.0.1", 1);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("9.9.9.9, 1.1.1.1"));
+
+        assert_eq!(client_ip(&headers, peer, true), "1.1.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(&headers, peer, false), peer.ip());
+        // A missing or garbled header falls back to the peer
+        assert_eq!(client_ip(&HeaderMap::new(), peer, true), peer.ip());
+        headers.insert("x-forwarded-for", HeaderValue::from_static("not an ip"));
+        assert_eq!(client_ip(&headers, peer, true), peer.ip());
+    }
+}
@@ -1,8 +1,13 @@
+pub mod connections;
 pub mod routes;
 pub mod ws;
 
-use axum::Router;
-use std::net::SocketAddr;
+use axum::{
+    extract::FromRef,
+    http::{header::AUTHORIZATION, HeaderMap},
+    Router,
+};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{sync::mpsc, task::JoinHandle};
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -11,13 +16,109 @@ use tower_http::{
 use tracing::info;
 use tycho_simulation::protocol::models::Update as BlockUpdate;
 
+use crate::errors::ApiError;
+use crate::simulation::arbitrage::ArbitrageScanner;
+use crate::simulation::history::PriceHistory;
+use crate::simulation::participation::ParticipationTracker;
+use crate::simulation::sandbox::SandboxStore;
 use crate::simulation::state::SimulationState;
 
+use self::connections::ConnectionRegistry;
 use self::routes::get_routes;
 
+/// Bearer token guarding the admin endpoints, which are off without one
+#[derive(Debug, Clone, Default)]
+pub struct AdminToken(Option<Arc<str>>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        AdminToken(token.filter(|token| !token.is_empty()).map(Arc::from))
+    }
+
+    /// Check the request's `Authorization: Bearer` header against the token
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let Some(expected) = self.0.as_deref() else {
+            return Err(ApiError::NotFound(
+                "Admin endpoints are disabled, set ADMIN_TOKEN to enable them".to_string(),
+            ));
+        };
+        let provided = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compare every byte so the time taken doesn't reveal the matching prefix
+        let matches = provided.len() == expected.len()
+            && provided
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        if matches {
+            Ok(())
+        } else {
+            Err(ApiError::Unauthorized("Invalid admin token".to_string()))
+        }
+    }
+}
+
+/// Shared state handed to every route
+#[derive(Clone)]
+pub struct AppState {
+    pub simulation: SimulationState,
+    pub connections: ConnectionRegistry,
+    pub sandboxes: SandboxStore,
+    pub participation: ParticipationTracker,
+    pub arbitrage: ArbitrageScanner,
+    pub history: PriceHistory,
+    pub admin_token: AdminToken,
+}
+
+impl FromRef<AppState> for SimulationState {
+    fn from_ref(state: &AppState) -> Self {
+        state.simulation.clone()
+    }
+}
+
+impl FromRef<AppState> for ConnectionRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.connections.clone()
+    }
+}
+
+impl FromRef<AppState> for SandboxStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.sandboxes.clone()
+    }
+}
+
+impl FromRef<AppState> for ParticipationTracker {
+    fn from_ref(state: &AppState) -> Self {
+        state.participation.clone()
+    }
+}
+
+impl FromRef<AppState> for ArbitrageScanner {
+    fn from_ref(state: &AppState) -> Self {
+        state.arbitrage.clone()
+    }
+}
+
+impl FromRef<AppState> for PriceHistory {
+    fn from_ref(state: &AppState) -> Self {
+        state.history.clone()
+    }
+}
+
+impl FromRef<AppState> for AdminToken {
+    fn from_ref(state: &AppState) -> Self {
+        state.admin_token.clone()
+    }
+}
+
 pub fn start_api_server(
     port: u16,
-    state: SimulationState,
+    app_state: AppState,
     _tx: mpsc::Sender<BlockUpdate>,
 ) -> JoinHandle<anyhow::Result<()>> {
     tokio::spawn(async move {
@@ -29,7 +130,7 @@ pub fn start_api_server(
 
         // Build the API routes
         let app = Router::new()
-            .merge(get_routes(state))
+            .merge(get_routes(app_state))
             .layer(TraceLayer::new_for_http())
             .layer(cors);
 
@@ -43,8 +144,12 @@ pub fn start_api_server(
         socket.bind(addr)?;
         let listener = socket.listen(1024)?;
 
-        // Start the server using the new approach
-        axum::serve(listener, app).await?;
+        // Start the server, keeping peer addresses around for per-IP connection limits
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
 
         anyhow::Result::<()>::Ok(())
     })
@@ -1,22 +1,71 @@
 use axum::{
-    extract::State,
+    extract::{Path, Query, State},
+    http::HeaderMap,
     routing::{get, post},
     Json, Router,
 };
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::info;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, info};
 
 use crate::errors::ApiError;
-use crate::simulation::state::SimulationState;
+use crate::simulation::amounts::{format_amount, parse_amount, AmountUnit};
+use crate::simulation::arbitrage::{ArbitrageScanner, ScanReport};
+use crate::simulation::book::{order_book, BookOptions, OrderBook};
+use crate::simulation::curve::{price_curve, PriceCurve};
+use crate::simulation::history::{HistoryPoint, PriceHistory};
+use crate::simulation::inspect::{pool_detail, PoolDetail};
+use crate::simulation::liquidity::{liquidity_distribution, LiquidityDistribution};
+use crate::simulation::overrides::{apply_what_if, WhatIf};
+use crate::simulation::pairs::{compare_pair, PairComparison};
+use crate::simulation::participation::{
+    analyze, GridRun, ParticipationReport, ParticipationTracker, TradeGrid,
+};
+use crate::simulation::pricing::GasCost;
+use crate::simulation::quarantine::QuarantineEntry;
+use crate::simulation::router::{best_route, RouteOptions};
+use crate::simulation::sandbox::{swap_in_sandbox, SandboxStore, SandboxSummary};
+use crate::simulation::spot_prices::SpotPriceMetrics;
+use crate::simulation::state::{BlockSnapshot, BlockSummary, SimulationState};
+use crate::simulation::swap::{
+    resolve_path, simulate_path, to_units, HopOutcome, HopRequest, LimitMode, ResolvedHop,
+    RouteOutcome, UnfilledRemainder,
+};
 
+use super::connections::{ConnectionRegistry, SessionSummary};
 use super::ws::ws_handler;
+use super::{AdminToken, AppState};
 
-pub fn get_routes(state: SimulationState) -> Router {
+pub fn get_routes(state: AppState) -> Router {
     Router::new()
         .route("/", get(health_check))
         .route("/api/simulate", post(simulate_transaction))
+        .route("/api/quote", post(get_quote))
+        .route("/api/pools", get(list_pools))
+        .route("/api/pools/:id", get(get_pool))
+        .route("/api/pools/:id/curve", get(get_pool_curve))
+        .route("/api/pools/:id/history", get(get_pool_history))
+        .route("/api/pools/:id/liquidity", get(get_pool_liquidity))
+        .route("/api/pairs/:base/:quote", get(get_pair))
+        .route("/api/pairs/:base/:quote/book", get(get_pair_book))
+        .route("/api/arbitrage", get(get_arbitrage))
+        .route("/api/whatif", post(what_if))
+        .route(
+            "/api/analysis/participation",
+            get(get_participation).post(run_participation),
+        )
+        .route("/api/sessions", post(create_session))
+        .route("/api/sessions/:id", get(get_session).delete(discard_session))
+        .route("/api/sessions/:id/swap", post(swap_in_session))
+        .route("/api/sessions/:id/simulate", post(simulate_in_session))
+        .route("/api/sessions/:id/quote", post(quote_in_session))
+        .route("/api/sessions/:id/overrides", post(override_in_session))
+        .route("/api/blocks/latest", get(get_latest_block))
+        .route("/api/metrics", get(get_metrics))
+        .route("/api/diagnostics/pools", get(get_pool_diagnostics))
+        .route("/api/admin/connections", get(list_connections))
         .route("/ws", get(ws_handler))
         .with_state(state)
 }
@@ -28,11 +77,131 @@ async fn health_check() -> Json<serde_json::Value> {
     }))
 }
 
-#[derive(Debug, Deserialize)]
+async fn get_latest_block(State(state): State<SimulationState>) -> Json<BlockSummary> {
+    Json(state.get_latest_block())
+}
+
+#[derive(Debug, Serialize)]
+struct MetricsResponse {
+    spot_prices: SpotPriceMetrics,
+}
+
+async fn get_metrics(State(state): State<SimulationState>) -> Json<MetricsResponse> {
+    Json(MetricsResponse {
+        spot_prices: state.spot_price_metrics(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct PoolDiagnosticsResponse {
+    block_number: u64,
+    total_pools: usize,
+    quarantined: Vec<QuarantineEntry>,
+}
+
+async fn get_pool_diagnostics(
+    State(state): State<SimulationState>,
+) -> Json<PoolDiagnosticsResponse> {
+    let snapshot = state.snapshot();
+    let mut quarantined: Vec<QuarantineEntry> = snapshot.quarantined.values().cloned().collect();
+    quarantined.sort_by_key(|entry| std::cmp::Reverse(entry.last_failed_block));
+    Json(PoolDiagnosticsResponse {
+        block_number: snapshot.block_number,
+        total_pools: snapshot.states.len(),
+        quarantined,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectionsResponse {
+    count: usize,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    current_block: u64,
+    connections: Vec<SessionSummary>,
+}
+
+/// Every open websocket session, including client IPs. Needs the admin token.
+async fn list_connections(
+    State(state): State<SimulationState>,
+    State(connections): State<ConnectionRegistry>,
+    State(admin_token): State<AdminToken>,
+    headers: HeaderMap,
+) -> Result<Json<ConnectionsResponse>, ApiError> {
+    admin_token.authorize(&headers)?;
+    let current_block = state.current_block();
+    let sessions = connections.list(current_block);
+    let limits = connections.limits();
+    Ok(Json(ConnectionsResponse {
+        count: sessions.len(),
+        max_connections: limits.max_connections,
+        max_connections_per_ip: limits.max_connections_per_ip,
+        current_block,
+        connections: sessions,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct SimulationRequest {
-    sell_token: String,
+    #[serde(default)]
+    sell_token: Option<String>,
+    #[serde(default)]
     pools: Vec<String>,
     amount: String,  // Accept as string to preserve precision
+    // Whether `amount` is in whole tokens or raw base units
+    #[serde(default)]
+    amount_unit: AmountUnit,
+    // Token bought from each pool, required for pools with more than two tokens
+    #[serde(default)]
+    buy_tokens: Option<Vec<String>>,
+    // Extended format: explicit pool, token in and token out for every hop
+    #[serde(default)]
+    hops: Option<Vec<HopRequest>>,
+    // Sell only what each pool can take instead of failing on oversized amounts
+    #[serde(default)]
+    cap_to_limits: bool,
+}
+
+impl SimulationRequest {
+    /// Normalise both request formats into a list of hops
+    fn hop_requests(&self) -> Result<Vec<HopRequest>, ApiError> {
+        if let Some(hops) = &self.hops {
+            if !self.pools.is_empty() || self.buy_tokens.is_some() {
+                return Err(ApiError::InvalidInput(
+                    "Use either hops or pools/buy_tokens, not both".to_string(),
+                ));
+            }
+            return Ok(hops.clone());
+        }
+
+        if self.sell_token.is_none() {
+            return Err(ApiError::InvalidInput(
+                "sell_token is required when routing by pools".to_string(),
+            ));
+        }
+        if let Some(buy_tokens) = &self.buy_tokens {
+            if buy_tokens.len() != self.pools.len() {
+                return Err(ApiError::InvalidInput(format!(
+                    "Expected one buy token per pool, got {} buy tokens for {} pools",
+                    buy_tokens.len(),
+                    self.pools.len()
+                )));
+            }
+        }
+        Ok(self
+            .pools
+            .iter()
+            .enumerate()
+            .map(|(hop, pool)| HopRequest {
+                pool: pool.clone(),
+                token_in: None,
+                token_out: self
+                    .buy_tokens
+                    .as_ref()
+                    .map(|buy_tokens| buy_tokens[hop].clone()),
+            })
+            .collect())
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -40,7 +209,125 @@ struct SimulationResponse {
     success: bool,
     input_amount: String,  // Keep as string for exact representation
     output_amount: String, // Return as string to preserve precision
+    input_amount_raw: String,  // Input in the sell token's base units
+    filled_input_amount_raw: String, // Input actually sold, less when capped to limits
+    output_amount_raw: String, // Output in the buy token's base units
     gas_estimate: String,  // Serialize BigUint as string
+    block_number: u64,     // Block whose pool states were used
+    spot_price: Option<f64>,      // Route rate for an infinitesimal trade
+    effective_price: f64,         // Output per input actually achieved
+    price_impact_pct: Option<f64>,
+    // Gas valued at the configured gas price, absent without one
+    gas_cost: Option<GasCost>,
+    // Output left after paying for gas, when gas can be priced in the buy token
+    net_output_amount: Option<String>,
+    net_output_amount_raw: Option<String>,
+    hops: Vec<HopOutcome>,
+    // Input left unsold at hops capped to their pool's limit
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unfilled: Vec<UnfilledRemainder>,
+}
+
+/// A simulation request checked against a snapshot, ready to run
+struct PreparedSimulation {
+    hops: Vec<ResolvedHop>,
+    amount_in: BigUint,
+    limit_mode: LimitMode,
+}
+
+fn prepare_simulation(
+    snapshot: &BlockSnapshot,
+    request: &SimulationRequest,
+) -> Result<PreparedSimulation, ApiError> {
+    let hops = resolve_path(
+        snapshot,
+        request.sell_token.as_deref(),
+        &request.hop_requests()?,
+    )?;
+    let sell_token = &hops[0].token_in;
+    let amount_in = parse_amount(&request.amount, request.amount_unit, sell_token.decimals as u32)
+        .map_err(ApiError::InvalidInput)?;
+    let limit_mode = if request.cap_to_limits {
+        LimitMode::Cap
+    } else {
+        LimitMode::Enforce
+    };
+    Ok(PreparedSimulation {
+        hops,
+        amount_in,
+        limit_mode,
+    })
+}
+
+fn simulation_response(
+    state: &SimulationState,
+    snapshot: &BlockSnapshot,
+    request: SimulationRequest,
+    prepared: &PreparedSimulation,
+    outcome: RouteOutcome,
+) -> SimulationResponse {
+    let sell_decimals = prepared.hops[0].token_in.decimals as u32;
+    let buy_token = &prepared.hops[prepared.hops.len() - 1].token_out;
+
+    // Convert output amount back to human-readable format with proper decimals
+    let output_amount_str = format_amount(&outcome.amount_out, buy_token.decimals as u32);
+    let spot_price = outcome.spot_price();
+    let effective_price = to_units(&outcome.amount_out, buy_token.decimals as u32)
+        / to_units(&outcome.amount_in, sell_decimals);
+    let price_impact_pct = spot_price
+        .filter(|spot| *spot > 0.0)
+        .map(|spot| (spot - effective_price) / spot * 100.0);
+
+    debug!("Simulated {} -> {}", request.amount, output_amount_str);
+
+    let gas_cost = state.pricing().gas_cost(snapshot, &outcome.total_gas, buy_token);
+    let net_output = state
+        .pricing()
+        .net_amount_out(gas_cost.as_ref(), &outcome.amount_out, buy_token);
+
+    SimulationResponse {
+        success: true,
+        input_amount: request.amount,
+        output_amount: output_amount_str,
+        input_amount_raw: prepared.amount_in.to_string(),
+        filled_input_amount_raw: outcome.amount_in.to_string(),
+        output_amount_raw: outcome.amount_out.to_string(),
+        gas_estimate: outcome.total_gas.to_string(),
+        block_number: snapshot.block_number,
+        spot_price,
+        effective_price,
+        price_impact_pct,
+        gas_cost,
+        net_output_amount: net_output
+            .as_ref()
+            .map(|amount| format_amount(amount, buy_token.decimals as u32)),
+        net_output_amount_raw: net_output.map(|amount| amount.to_string()),
+        hops: outcome.hops,
+        unfilled: outcome.unfilled,
+    }
+}
+
+/// Simulate a route against `snapshot` without changing it
+async fn run_simulation(
+    state: &SimulationState,
+    snapshot: Arc<BlockSnapshot>,
+    request: SimulationRequest,
+) -> Result<SimulationResponse, ApiError> {
+    let prepared = prepare_simulation(&snapshot, &request)?;
+
+    // Pool simulations are CPU-heavy, run them off the async workers
+    let job_snapshot = snapshot.clone();
+    let job_hops = prepared.hops.clone();
+    let job_amount = prepared.amount_in.clone();
+    let limit_mode = prepared.limit_mode;
+    let outcome = state
+        .executor()
+        .run(move |cancel| {
+            simulate_path(&job_snapshot, &job_hops, job_amount, limit_mode, cancel)
+        })
+        .await??;
+
+    Ok(simulation_response(state, &snapshot, request, &prepared, outcome))
 }
 
 // Use Result with your existing ApiError
@@ -48,146 +335,625 @@ async fn simulate_transaction(
     State(state): State<SimulationState>,
     Json(request): Json<SimulationRequest>,
 ) -> Result<Json<SimulationResponse>, ApiError> {
-    // Log incoming request
-    info!("=== SIMULATE REQUEST ===");
-    info!("Sell Token: {}", request.sell_token);
-    info!("Pools: {:?}", request.pools);
-    info!("Amount: {}", request.amount);
-    
-    // Parse amount from string, handling decimal notation
-    let (integer_part, decimal_part) = if let Some(dot_pos) = request.amount.find('.') {
-        let (int_str, dec_str) = request.amount.split_at(dot_pos);
-        (int_str, &dec_str[1..]) // Skip the dot
-    } else {
-        (request.amount.as_str(), "")
-    };
-    
-    // Parse integer part as BigUint
-    let amount_biguint = BigUint::parse_bytes(integer_part.as_bytes(), 10)
-        .ok_or_else(|| ApiError::InvalidInput("Invalid amount format".to_string()))?;
-
-    let mut current_amount = None;
-    let mut total_gas = BigUint::from(0u64);
-    let mut next_sell_token = request.sell_token;
-    let mut decimals = 0;
-
-    for pool_address in request.pools.iter() {
-        let (component, pool_state) = state.get_pool_state(pool_address).await;
-
-        match pool_state {
-            Some(pool) => {
-                let sell_token;
-                let buy_token;
-                match component {
-                    Some(component) => {
-                        if component.tokens[0].address.to_string() == next_sell_token {
-                            sell_token = component.tokens[0].clone();
-                            buy_token = component.tokens[1].clone();
-                        } else {
-                            sell_token = component.tokens[1].clone();
-                            buy_token = component.tokens[0].clone();
-                        }
-                    }
-                    None => {
-                        return Err(ApiError::NotFound(format!(
-                            "Component not found: {}",
-                            pool_address
-                        )));
-                    }
-                }
-                if current_amount.is_none() {
-                    // Calculate 10^decimals using BigUint arithmetic
-                    let base = BigUint::from(10u32);
-                    let token_multiplier = base.pow(sell_token.decimals as u32);
-                    
-                    // Convert integer part to smallest unit
-                    let mut final_amount = amount_biguint.clone() * &token_multiplier;
-                    
-                    // Handle decimal part if present
-                    if !decimal_part.is_empty() {
-                        let decimal_places = decimal_part.len();
-                        if decimal_places > sell_token.decimals as usize {
-                            return Err(ApiError::InvalidInput(
-                                format!("Too many decimal places. Token supports {} decimals", sell_token.decimals)
-                            ));
-                        }
-                        
-                        // Parse decimal part and adjust for token decimals
-                        if let Some(decimal_value) = BigUint::parse_bytes(decimal_part.as_bytes(), 10) {
-                            let decimal_multiplier = base.pow((sell_token.decimals as usize - decimal_places) as u32);
-                            final_amount += decimal_value * decimal_multiplier;
-                        }
-                    }
-                    
-                    current_amount = Some(final_amount);
-                    
-                    info!("input amount: {}", request.amount);
-                    info!("sell_token decimals: {}", sell_token.decimals);
-                    info!("initial amount (with decimals): {}", current_amount.as_ref().unwrap());
-                }
-                
-                info!("=== POOL SIMULATION ===");
-                info!("Pool: {}", pool_address);
-                info!("Sell Token: {} (decimals: {})", sell_token.address, sell_token.decimals);
-                info!("Buy Token: {} (decimals: {})", buy_token.address, buy_token.decimals);
-                info!("Input Amount: {}", current_amount.as_ref().unwrap());
-                
-                let result = pool
-                    .get_amount_out(current_amount.unwrap(), &sell_token, &buy_token)
-                    .map_err(|e| ApiError::SimulationError(format!("Simulation error: {}", e)))?;
-                
-                info!("Output Amount: {}", result.amount);
-                info!("Gas Used: {}", result.gas);
-
-                // Assuming result is a tuple of (amount_out, gas)
-                current_amount = Some(result.amount);
-                total_gas += result.gas;
-                next_sell_token = buy_token.address.to_string();
-                decimals = buy_token.decimals;
-            }
-            None => {
-                return Err(ApiError::NotFound(format!(
-                    "Pool not found: {}",
-                    pool_address
-                )));
-            }
-        }
+    // One line per request, the per-hop details are logged at debug
+    info!(
+        "Simulate request: sell {:?}, amount {} ({:?}), pools {:?}, hops {:?}",
+        request.sell_token, request.amount, request.amount_unit, request.pools, request.hops
+    );
+
+    // Read every hop from the same block
+    let snapshot = state.snapshot();
+    Ok(Json(run_simulation(&state, snapshot, request).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct QuoteRequest {
+    sell_token: String,
+    buy_token: String,
+    amount: String,
+    #[serde(default)]
+    amount_unit: AmountUnit,
+    // Longest route to consider, defaults to 3 hops
+    #[serde(default)]
+    max_hops: Option<usize>,
+    // Number of routes to simulate, defaults to 20
+    #[serde(default)]
+    max_candidates: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct QuoteResponse {
+    success: bool,
+    block_number: u64,
+    input_amount: String,
+    input_amount_raw: String,
+    output_amount: String,
+    output_amount_raw: String,
+    gas_estimate: String,
+    gas_cost: Option<GasCost>,
+    net_output_amount: Option<String>,
+    net_output_amount_raw: Option<String>,
+    spot_price: Option<f64>,
+    // Number of routes simulated to pick this one
+    routes_simulated: usize,
+    hops: Vec<HopOutcome>,
+}
+
+/// Longest route a client may ask the router for
+const MAX_QUOTE_HOPS: usize = 4;
+/// Most routes a client may ask the router to simulate
+const MAX_QUOTE_CANDIDATES: usize = 100;
+
+/// Find the route through `snapshot` that returns the most of the buy token after gas
+async fn run_quote(
+    state: &SimulationState,
+    snapshot: Arc<BlockSnapshot>,
+    request: QuoteRequest,
+) -> Result<QuoteResponse, ApiError> {
+    let sell_token = snapshot
+        .find_token(&request.sell_token)
+        .ok_or_else(|| ApiError::NotFound(format!("Unknown sell token: {}", request.sell_token)))?;
+    let buy_token = snapshot
+        .find_token(&request.buy_token)
+        .ok_or_else(|| ApiError::NotFound(format!("Unknown buy token: {}", request.buy_token)))?
+        .clone();
+    let amount_in = parse_amount(&request.amount, request.amount_unit, sell_token.decimals as u32)
+        .map_err(ApiError::InvalidInput)?;
+
+    let mut options = RouteOptions::default();
+    if let Some(max_hops) = request.max_hops {
+        options.max_hops = max_hops.clamp(1, MAX_QUOTE_HOPS);
+    }
+    if let Some(max_candidates) = request.max_candidates {
+        options.max_candidates = max_candidates.clamp(1, MAX_QUOTE_CANDIDATES);
     }
 
-    let amount_out_raw = current_amount
-        .ok_or_else(|| ApiError::SimulationError("No output amount calculated".to_string()))?;
-    
-    info!("=== FINAL CALCULATION ===");
-    info!("Raw output amount: {}", amount_out_raw);
-    info!("Output decimals: {}", decimals);
-    
-    // Convert output amount back to human-readable format with proper decimals
-    let divisor = BigUint::from(10u32).pow(decimals as u32);
-    let integer_part = &amount_out_raw / &divisor;
-    let remainder = &amount_out_raw % &divisor;
-    
-    // Format output with decimal places
-    let output_amount_str = if remainder == BigUint::from(0u32) {
-        integer_part.to_string()
-    } else {
-        // Pad remainder with leading zeros if needed
-        let remainder_str = format!("{:0>width$}", remainder.to_string(), width = decimals as usize);
-        // Trim trailing zeros
-        let trimmed = remainder_str.trim_end_matches('0');
-        if trimmed.is_empty() {
-            integer_part.to_string()
-        } else {
-            format!("{}.{}", integer_part, trimmed)
-        }
-    };
-    
-    info!("Final output amount: {}", output_amount_str);
-    info!("Exchange rate: {} -> {}", request.amount, output_amount_str);
+    let job_snapshot = snapshot.clone();
+    let pricing = state.pricing().clone();
+    let job_amount = amount_in.clone();
+    let (sell, buy) = (request.sell_token.clone(), request.buy_token.clone());
+    let quote = state
+        .executor()
+        .run(move |cancel| {
+            best_route(&job_snapshot, &pricing, &sell, &buy, &job_amount, options, cancel)
+        })
+        .await??;
 
-    Ok(Json(SimulationResponse {
+    let buy_decimals = buy_token.decimals as u32;
+    Ok(QuoteResponse {
         success: true,
+        block_number: snapshot.block_number,
         input_amount: request.amount,
-        output_amount: output_amount_str,
-        gas_estimate: total_gas.to_string(),
+        input_amount_raw: amount_in.to_string(),
+        output_amount: format_amount(&quote.outcome.amount_out, buy_decimals),
+        output_amount_raw: quote.outcome.amount_out.to_string(),
+        gas_estimate: quote.outcome.total_gas.to_string(),
+        spot_price: quote.outcome.spot_price(),
+        gas_cost: quote.gas_cost,
+        net_output_amount: quote
+            .net_amount_out
+            .as_ref()
+            .map(|amount| format_amount(amount, buy_decimals)),
+        net_output_amount_raw: quote.net_amount_out.map(|amount| amount.to_string()),
+        routes_simulated: quote.simulated,
+        hops: quote.outcome.hops,
+    })
+}
+
+async fn get_quote(
+    State(state): State<SimulationState>,
+    Json(request): Json<QuoteRequest>,
+) -> Result<Json<QuoteResponse>, ApiError> {
+    let snapshot = state.snapshot();
+    Ok(Json(run_quote(&state, snapshot, request).await?))
+}
+
+#[derive(Debug, Serialize)]
+struct CreateSessionResponse {
+    success: bool,
+    id: String,
+    block_number: u64,
+    // Idle sessions are discarded after this long
+    idle_timeout_secs: u64,
+}
+
+/// Fork a sandbox from the latest block
+async fn create_session(
+    State(state): State<SimulationState>,
+    State(sandboxes): State<SandboxStore>,
+) -> Result<Json<CreateSessionResponse>, ApiError> {
+    let snapshot = state.snapshot();
+    let block_number = snapshot.block_number;
+    let id = sandboxes.create(snapshot)?;
+    Ok(Json(CreateSessionResponse {
+        success: true,
+        id,
+        block_number,
+        idle_timeout_secs: sandboxes.config().idle_timeout.as_secs(),
     }))
 }
+
+async fn get_session(
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+) -> Result<Json<SandboxSummary>, ApiError> {
+    let sandbox = sandboxes.get(&id)?;
+    let sandbox = sandbox.lock().await;
+    Ok(Json(sandbox.summary()))
+}
+
+async fn discard_session(
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !sandboxes.discard(&id) {
+        return Err(ApiError::NotFound(format!("Session not found: {}", id)));
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+/// Apply a swap to a sandbox, later requests see the pools it moved
+async fn swap_in_session(
+    State(state): State<SimulationState>,
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+    Json(request): Json<SimulationRequest>,
+) -> Result<Json<SimulationResponse>, ApiError> {
+    let sandbox = sandboxes.get(&id)?;
+    // Held until the swap is applied so swaps on one session run in order
+    let mut sandbox = sandbox.lock().await;
+    let snapshot = sandbox.snapshot.clone();
+    let prepared = prepare_simulation(&snapshot, &request)?;
+
+    let job_snapshot = snapshot.clone();
+    let job_hops = prepared.hops.clone();
+    let job_amount = prepared.amount_in.clone();
+    let limit_mode = prepared.limit_mode;
+    let (outcome, next) = state
+        .executor()
+        .run(move |cancel| {
+            swap_in_sandbox(&job_snapshot, &job_hops, job_amount, limit_mode, cancel)
+        })
+        .await??;
+
+    sandbox.apply(&prepared.hops, &outcome, next);
+    Ok(Json(simulation_response(&state, &snapshot, request, &prepared, outcome)))
+}
+
+/// Simulate a route against a sandbox without applying it
+async fn simulate_in_session(
+    State(state): State<SimulationState>,
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+    Json(request): Json<SimulationRequest>,
+) -> Result<Json<SimulationResponse>, ApiError> {
+    let snapshot = sandboxes.get(&id)?.lock().await.snapshot.clone();
+    Ok(Json(run_simulation(&state, snapshot, request).await?))
+}
+
+async fn quote_in_session(
+    State(state): State<SimulationState>,
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+    Json(request): Json<QuoteRequest>,
+) -> Result<Json<QuoteResponse>, ApiError> {
+    let snapshot = sandboxes.get(&id)?.lock().await.snapshot.clone();
+    Ok(Json(run_quote(&state, snapshot, request).await?))
+}
+
+/// Apply overrides to a sandbox, later requests in the session see them
+async fn override_in_session(
+    State(state): State<SimulationState>,
+    State(sandboxes): State<SandboxStore>,
+    Path(id): Path<String>,
+    Json(request): Json<WhatIf>,
+) -> Result<Json<SandboxSummary>, ApiError> {
+    let sandbox = sandboxes.get(&id)?;
+    let mut sandbox = sandbox.lock().await;
+    let snapshot = sandbox.snapshot.clone();
+    let numeraire = state.pricing().numeraire.clone();
+    let (next, changed) = state
+        .executor()
+        .run(move |_| apply_what_if(&snapshot, &request, &numeraire))
+        .await??;
+    sandbox.apply_overrides(next, changed);
+    Ok(Json(sandbox.summary()))
+}
+
+#[derive(Debug, Deserialize)]
+struct WhatIfRequest {
+    #[serde(flatten)]
+    what_if: WhatIf,
+    // Route to simulate against the modified pools
+    #[serde(default)]
+    simulate: Option<SimulationRequest>,
+    // Trade to route through the modified pools
+    #[serde(default)]
+    quote: Option<QuoteRequest>,
+}
+
+#[derive(Debug, Serialize)]
+struct WhatIfResponse {
+    success: bool,
+    block_number: u64,
+    // Spot price of every overridden or synthetic pool under the new state
+    modified_pools: HashMap<String, Option<f64>>,
+    simulation: Option<SimulationResponse>,
+    quote: Option<QuoteResponse>,
+}
+
+/// Re-run a simulation and/or quote against a copy of the latest block with
+/// some pools changed. The live state is left untouched.
+async fn what_if(
+    State(state): State<SimulationState>,
+    Json(request): Json<WhatIfRequest>,
+) -> Result<Json<WhatIfResponse>, ApiError> {
+    let live = state.snapshot();
+    let what_if = request.what_if;
+    let numeraire = state.pricing().numeraire.clone();
+    let (modified, changed) = state
+        .executor()
+        .run(move |_| apply_what_if(&live, &what_if, &numeraire))
+        .await??;
+    let modified = Arc::new(modified);
+
+    let simulation = match request.simulate {
+        Some(simulate) => Some(run_simulation(&state, modified.clone(), simulate).await?),
+        None => None,
+    };
+    let quote = match request.quote {
+        Some(quote) => Some(run_quote(&state, modified.clone(), quote).await?),
+        None => None,
+    };
+
+    Ok(Json(WhatIfResponse {
+        success: true,
+        block_number: modified.block_number,
+        modified_pools: changed
+            .into_iter()
+            .map(|pool| {
+                let price = modified.spot_prices.get(&pool).copied();
+                (pool, price)
+            })
+            .collect(),
+        simulation,
+        quote,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ParticipationQuery {
+    // Only pools trading this token
+    token: Option<String>,
+    // Only this pool
+    pool: Option<String>,
+}
+
+/// Latest report of the background participation analysis
+async fn get_participation(
+    State(tracker): State<ParticipationTracker>,
+    Query(query): Query<ParticipationQuery>,
+) -> Result<Json<ParticipationReport>, ApiError> {
+    let report = tracker.latest().ok_or_else(|| {
+        ApiError::NotFound(
+            "No participation report yet, configure --participation-pairs or POST a grid"
+                .to_string(),
+        )
+    })?;
+    Ok(Json(report.filtered(query.token.as_deref(), query.pool.as_deref())))
+}
+
+/// Run a participation analysis over the given grid against the latest block.
+/// Only one requested analysis runs at a time.
+async fn run_participation(
+    State(state): State<SimulationState>,
+    State(tracker): State<ParticipationTracker>,
+    Query(query): Query<ParticipationQuery>,
+    Json(grid): Json<TradeGrid>,
+) -> Result<Json<ParticipationReport>, ApiError> {
+    grid.validate()?;
+    let _running = tracker.try_start().ok_or_else(|| {
+        ApiError::Overloaded("A participation analysis is already running".to_string())
+    })?;
+    let report = analyze(&state, &grid, GridRun::Request).await?;
+    Ok(Json(report.filtered(query.token.as_deref(), query.pool.as_deref())))
+}
+
+/// Default and largest page of the pool list
+const DEFAULT_POOL_PAGE: usize = 100;
+const MAX_POOL_PAGE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PoolSort {
+    #[default]
+    Fee,
+    SpotPrice,
+    Protocol,
+}
+
+#[derive(Debug, Deserialize)]
+struct PoolListQuery {
+    #[serde(default)]
+    sort: PoolSort,
+    // Ascending unless set
+    #[serde(default)]
+    desc: bool,
+    #[serde(default)]
+    protocol: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PoolListEntry {
+    pool: String,
+    protocol_system: String,
+    tokens: Vec<String>,
+    fee_bps: Option<f64>,
+    spot_price: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PoolListResponse {
+    block_number: u64,
+    total: usize,
+    pools: Vec<PoolListEntry>,
+}
+
+/// Every pool with its fee and spot price, filtered and sorted
+async fn list_pools(
+    State(state): State<SimulationState>,
+    Query(query): Query<PoolListQuery>,
+) -> Json<PoolListResponse> {
+    let snapshot = state.snapshot();
+    let mut pools: Vec<PoolListEntry> = snapshot
+        .components
+        .iter()
+        .filter(|(_, component)| {
+            query
+                .protocol
+                .as_deref()
+                .is_none_or(|protocol| component.protocol_system == protocol)
+        })
+        .filter(|(_, component)| {
+            query.token.as_deref().is_none_or(|token| {
+                component
+                    .tokens
+                    .iter()
+                    .any(|t| t.address.to_string().eq_ignore_ascii_case(token))
+            })
+        })
+        .map(|(id, component)| PoolListEntry {
+            pool: id.clone(),
+            protocol_system: component.protocol_system.clone(),
+            tokens: component.tokens.iter().map(|t| t.address.to_string()).collect(),
+            fee_bps: snapshot.fees.get(id).copied(),
+            spot_price: snapshot.spot_prices.get(id).copied(),
+        })
+        .collect();
+
+    // Pools without a value sort last either way
+    let by_value = |a: Option<f64>, b: Option<f64>| match (a, b) {
+        (Some(a), Some(b)) if query.desc => b.total_cmp(&a),
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    };
+    pools.sort_by(|a, b| {
+        let order = match query.sort {
+            PoolSort::Fee => by_value(a.fee_bps, b.fee_bps),
+            PoolSort::SpotPrice => by_value(a.spot_price, b.spot_price),
+            PoolSort::Protocol if query.desc => b.protocol_system.cmp(&a.protocol_system),
+            PoolSort::Protocol => a.protocol_system.cmp(&b.protocol_system),
+        };
+        order.then_with(|| a.pool.cmp(&b.pool))
+    });
+
+    let total = pools.len();
+    let limit = query.limit.unwrap_or(DEFAULT_POOL_PAGE).min(MAX_POOL_PAGE);
+    Json(PoolListResponse {
+        block_number: snapshot.block_number,
+        total,
+        pools: pools.into_iter().skip(query.offset).take(limit).collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct PoolQuery {
+    // Comma-separated tokens to quote the price in, first match wins
+    #[serde(default)]
+    quote_tokens: Option<String>,
+}
+
+/// Tokens, prices and protocol-specific state of one pool
+async fn get_pool(
+    State(state): State<SimulationState>,
+    Path(id): Path<String>,
+    Query(query): Query<PoolQuery>,
+) -> Result<Json<PoolDetail>, ApiError> {
+    let quote_tokens: Vec<String> = query
+        .quote_tokens
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|token| token.trim().to_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+    let snapshot = state.snapshot();
+    Ok(Json(pool_detail(&snapshot, &id, &quote_tokens)?))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    // Block range, both ends inclusive
+    #[serde(default)]
+    from: Option<u64>,
+    #[serde(default)]
+    to: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryResponse {
+    pool: String,
+    points: Vec<HistoryPoint>,
+}
+
+/// Recorded spot price and TVL of a pool, oldest first
+async fn get_pool_history(
+    State(history): State<PriceHistory>,
+    Path(id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ApiError::InvalidInput("from must not be after to".to_string()));
+        }
+    }
+    let points = history
+        .range(&id, query.from, query.to)
+        .ok_or_else(|| ApiError::NotFound(format!("No history for pool {}", id)))?;
+    Ok(Json(HistoryResponse { pool: id, points }))
+}
+
+/// Default and largest number of points on a pool curve
+const DEFAULT_CURVE_POINTS: usize = 20;
+const MAX_CURVE_POINTS: usize = 200;
+
+#[derive(Debug, Deserialize)]
+struct CurveQuery {
+    // Token indices to swap between, e.g. "0to1"
+    #[serde(default)]
+    direction: Option<String>,
+    #[serde(default)]
+    points: Option<usize>,
+}
+
+/// Output, effective price and impact for log-spaced sizes up to the pool limit
+async fn get_pool_curve(
+    State(state): State<SimulationState>,
+    Path(id): Path<String>,
+    Query(query): Query<CurveQuery>,
+) -> Result<Json<PriceCurve>, ApiError> {
+    let snapshot = state.snapshot();
+    let direction = query.direction.unwrap_or_else(|| "0to1".to_string());
+    let points = query
+        .points
+        .unwrap_or(DEFAULT_CURVE_POINTS)
+        .clamp(1, MAX_CURVE_POINTS);
+    let curve = state
+        .executor()
+        .run(move |cancel| price_curve(&snapshot, &id, &direction, points, cancel))
+        .await??;
+    Ok(Json(curve))
+}
+
+/// Default and largest number of tick ranges on each side of the current price
+const DEFAULT_LIQUIDITY_RANGES: usize = 50;
+const MAX_LIQUIDITY_RANGES: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct LiquidityQuery {
+    #[serde(default)]
+    ranges: Option<usize>,
+}
+
+/// Active liquidity per tick range of a concentrated liquidity pool
+async fn get_pool_liquidity(
+    State(state): State<SimulationState>,
+    Path(id): Path<String>,
+    Query(query): Query<LiquidityQuery>,
+) -> Result<Json<LiquidityDistribution>, ApiError> {
+    let ranges = query
+        .ranges
+        .unwrap_or(DEFAULT_LIQUIDITY_RANGES)
+        .min(MAX_LIQUIDITY_RANGES);
+    let snapshot = state.snapshot();
+    Ok(Json(liquidity_distribution(&snapshot, &id, ranges)?))
+}
+
+/// Largest number of price levels per side of a book
+const MAX_BOOK_LEVELS: usize = 200;
+/// Largest number of sizes sampled per pool for a book
+const MAX_BOOK_SAMPLES: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct BookQuery {
+    #[serde(default)]
+    levels: Option<usize>,
+    // Level width in basis points of the mid price
+    #[serde(default)]
+    step_bps: Option<f64>,
+    // Sizes sampled per pool and side
+    #[serde(default)]
+    samples: Option<usize>,
+}
+
+/// Depth of every pool trading the pair, merged into bid and ask levels
+async fn get_pair_book(
+    State(state): State<SimulationState>,
+    Path((base, quote)): Path<(String, String)>,
+    Query(query): Query<BookQuery>,
+) -> Result<Json<OrderBook>, ApiError> {
+    let step_bps = query.step_bps.unwrap_or(10.0);
+    if !(step_bps.is_finite() && step_bps > 0.0) {
+        return Err(ApiError::InvalidInput("step_bps must be positive".to_string()));
+    }
+    let options = BookOptions {
+        levels: query.levels.unwrap_or(50).clamp(1, MAX_BOOK_LEVELS),
+        step_bps,
+        samples: query.samples.unwrap_or(24).clamp(2, MAX_BOOK_SAMPLES),
+    };
+    let snapshot = state.snapshot();
+    let book = state
+        .executor()
+        .run(move |cancel| order_book(&snapshot, &base, &quote, options, cancel))
+        .await??;
+    Ok(Json(book))
+}
+
+#[derive(Debug, Deserialize)]
+struct PairQuery {
+    // Price impact, in percent, at which pool depth is measured
+    #[serde(default)]
+    depth_pct: Option<f64>,
+}
+
+/// Every pool on a pair with TVL, depth, fee, gas and deviation from the mid price
+async fn get_pair(
+    State(state): State<SimulationState>,
+    Path((base, quote)): Path<(String, String)>,
+    Query(query): Query<PairQuery>,
+) -> Result<Json<PairComparison>, ApiError> {
+    let depth_pct = query.depth_pct.unwrap_or(2.0);
+    if !(depth_pct.is_finite() && depth_pct > 0.0 && depth_pct < 100.0) {
+        return Err(ApiError::InvalidInput(
+            "depth_pct must be between 0 and 100".to_string(),
+        ));
+    }
+    let snapshot = state.snapshot();
+    let numeraire = state.pricing().numeraire.clone();
+    let comparison = state
+        .executor()
+        .run(move |cancel| compare_pair(&snapshot, &numeraire, &base, &quote, depth_pct, cancel))
+        .await??;
+    Ok(Json(comparison))
+}
+
+/// Deviating pairs and verified arbitrage cycles found in the latest block
+async fn get_arbitrage(
+    State(scanner): State<ArbitrageScanner>,
+) -> Result<Json<ScanReport>, ApiError> {
+    if !scanner.is_enabled() {
+        return Err(ApiError::NotFound(
+            "The arbitrage scanner is disabled (--no-arbitrage-scanner)".to_string(),
+        ));
+    }
+    let report = scanner.latest().ok_or_else(|| {
+        ApiError::NotFound("No arbitrage scan has completed yet".to_string())
+    })?;
+    Ok(Json(report.as_ref().clone()))
+}
@@ -1,82 +1,289 @@
 use axum::{
-    extract::{State, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        ConnectInfo, Query, State, WebSocketUpgrade,
+    },
+    http::HeaderMap,
     response::IntoResponse,
 };
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::broadcast;
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::simulation::{
+    arbitrage::ArbitrageScanner,
+    spot_prices::OrientedPrice,
+    state::{ClientUpdate, SimulationState},
+};
+
+use super::connections::{client_ip, ConnectionRegistry, SessionInfo};
+
+/// Topic every client is subscribed to on connect, unsubscribing pauses the
+/// block updates until it is subscribed again
+const BLOCK_UPDATES_TOPIC: &str = "block_updates";
+/// Opt-in topic carrying a scan report per block
+const ARBITRAGE_TOPIC: &str = "arbitrage";
+
+/// Messages a client may send
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    /// Quote the oriented prices of every update in the first of `tokens` a
+    /// pool trades, instead of the pool's own token order
+    QuoteTokens { tokens: Vec<String> },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectQuery {
+    // Comma-separated quote tokens, same as sending a quote_tokens message
+    #[serde(default)]
+    quote_tokens: Option<String>,
+}
 
-use crate::simulation::state::SimulationState;
+/// A block update with its spot prices in both directions, oriented for one
+/// session
+#[derive(Serialize)]
+struct OrientedUpdate<'a> {
+    #[serde(flatten)]
+    update: &'a ClientUpdate,
+    prices: HashMap<String, OrientedPrice>,
+}
+
+fn serialize_update(
+    update: &ClientUpdate,
+    session: &SessionInfo,
+    state: &SimulationState,
+) -> serde_json::Result<String> {
+    // Without quote tokens prices keep the pool's own token order
+    let quote_tokens = session.quote_tokens().unwrap_or_default();
+    serde_json::to_string(&OrientedUpdate {
+        update,
+        prices: update.oriented_prices(&state.snapshot(), &quote_tokens),
+    })
+}
 
 pub async fn ws_handler(
     State(state): State<SimulationState>,
+    State(connections): State<ConnectionRegistry>,
+    State(scanner): State<ArbitrageScanner>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ConnectQuery>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|websocket| handle_socket(websocket, state))
+    let client_ip = client_ip(&headers, remote_addr, connections.limits().trust_forwarded_for);
+    let quote_tokens = query.quote_tokens.map(|tokens| {
+        tokens
+            .split(',')
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .collect()
+    });
+    ws.on_upgrade(move |websocket| {
+        handle_socket(websocket, state, connections, scanner, quote_tokens, remote_addr, client_ip)
+    })
 }
 
-async fn handle_socket(websocket: axum::extract::ws::WebSocket, state: SimulationState) {
-    info!("New WebSocket connection established");
+async fn close_with_reason(mut websocket: WebSocket, code: u16, reason: String) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = websocket.send(Message::Close(Some(frame))).await {
+        error!("Error sending close frame: {}", e);
+    }
+}
+
+async fn handle_socket(
+    websocket: WebSocket,
+    state: SimulationState,
+    connections: ConnectionRegistry,
+    scanner: ArbitrageScanner,
+    quote_tokens: Option<Vec<String>>,
+    remote_addr: SocketAddr,
+    client_ip: IpAddr,
+) {
+    let guard = match connections.register(remote_addr, client_ip) {
+        Ok(guard) => guard,
+        Err(rejection) => {
+            info!("Rejecting WebSocket connection from {}: {}", client_ip, rejection);
+            close_with_reason(websocket, close_code::AGAIN, rejection.to_string()).await;
+            return;
+        }
+    };
+    let session = guard.info();
+    if let Some(quote_tokens) = quote_tokens {
+        session.set_quote_tokens(quote_tokens);
+    }
+    let limits = connections.limits().clone();
+    info!("New WebSocket connection established (session {}, {})", session.id, client_ip);
 
     // Split the socket into sender and receiver
     let (mut sender, mut receiver) = websocket.split();
 
     // Subscribe to simulation updates
-    let mut update_rx = state.subscribe_to_updates();
+    let subscription = state.subscribe_to_updates();
+    session.subscribe(BLOCK_UPDATES_TOPIC);
+    let mut arbitrage_reports = scanner.subscribe();
 
     // Send current state immediately when a client connects
-    let latest_block = state.get_full_state().await;
-    if let Ok(msg) = serde_json::to_string(&latest_block) {
-        if let Err(e) = sender.send(axum::extract::ws::Message::Text(msg)).await {
+    let latest_block = state.get_full_state();
+    if let Ok(msg) = serialize_update(&latest_block, &session, &state) {
+        if let Err(e) = sender.send(Message::Text(msg)).await {
             error!("Error sending initial state: {}", e);
             return;
         }
+        session.record_sent(latest_block.block_number);
     }
 
-    // Spawn a task to handle sending updates to the client
-    let send_task = tokio::spawn(async move {
+    // Spawn a task to handle sending updates and heartbeats to the client
+    let send_session = session.clone();
+    let send_state = state.clone();
+    let mut send_task = tokio::spawn(async move {
+        let session = send_session;
+        let mut ping_interval = tokio::time::interval(limits.ping_interval);
+        // The first tick completes immediately, there is no point pinging right after connect
+        ping_interval.tick().await;
+        let mut pong_deadline: Option<Instant> = None;
+        // Set while block updates are skipped, the next one sent is the full state
+        let mut missed_updates = false;
+
         loop {
-            // Receive the next update from the broadcast channel
-            let update = match update_rx.recv().await {
-                Ok(update) => update,
-                Err(broadcast::error::RecvError::Closed) => {
-                    info!("Update channel closed");
-                    break;
-                }
-                Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                    error!("Client lagging behind, skipped {} messages", skipped);
-                    continue;
+            let deadline = async move {
+                match pong_deadline {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => std::future::pending().await,
                 }
             };
 
-            // Serialize the update to send to the client
-            let msg = match serde_json::to_string(&update) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    error!("Error serializing update: {}", e);
-                    continue;
-                }
-            };
+            tokio::select! {
+                update = subscription.outbox().recv() => {
+                    // Updates that arrived while we were busy have been merged into this one
+                    let coalesced = subscription.outbox().take_coalesced();
+                    if coalesced > 0 {
+                        debug!("Session {} coalesced {} updates", session.id, coalesced);
+                        session.record_coalesced(coalesced);
+                    }
+                    if !session.is_subscribed(BLOCK_UPDATES_TOPIC) {
+                        missed_updates = true;
+                        continue;
+                    }
+
+                    // Updates are diffs, after a gap the client needs the whole state again
+                    let full_state;
+                    let update = if std::mem::take(&mut missed_updates) {
+                        full_state = send_state.get_full_state();
+                        &full_state
+                    } else {
+                        update.as_ref()
+                    };
+
+                    // Serialize the update to send to the client
+                    let msg = match serialize_update(update, &session, &send_state) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            error!("Error serializing update: {}", e);
+                            continue;
+                        }
+                    };
 
-            // Send the update to the client
-            if let Err(e) = sender.send(axum::extract::ws::Message::Text(msg)).await {
-                error!("Error sending message: {}", e);
-                break;
+                    // Send the update to the client
+                    if let Err(e) = sender.send(Message::Text(msg)).await {
+                        error!("Error sending message: {}", e);
+                        break;
+                    }
+                    session.record_sent(update.block_number);
+                }
+                report = arbitrage_reports.recv() => {
+                    let report = match report {
+                        Ok(report) => report,
+                        // Only the newest report matters, skipping missed ones is fine
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => {
+                            arbitrage_reports = scanner.subscribe();
+                            continue;
+                        }
+                    };
+                    if !session.is_subscribed(ARBITRAGE_TOPIC) {
+                        continue;
+                    }
+                    let msg = match serde_json::to_string(&serde_json::json!({
+                        "topic": ARBITRAGE_TOPIC,
+                        "data": report.as_ref(),
+                    })) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            error!("Error serializing arbitrage report: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = sender.send(Message::Text(msg)).await {
+                        error!("Error sending message: {}", e);
+                        break;
+                    }
+                }
+                _ = ping_interval.tick() => {
+                    if pong_deadline.is_some() {
+                        continue;
+                    }
+                    if let Err(e) = sender.send(Message::Ping(Vec::new())).await {
+                        error!("Error sending ping: {}", e);
+                        break;
+                    }
+                    session.record_ping();
+                    pong_deadline = Some(Instant::now() + limits.pong_timeout);
+                }
+                _ = deadline => {
+                    if !session.awaiting_pong() {
+                        pong_deadline = None;
+                        continue;
+                    }
+                    info!("Session {} missed pong deadline, closing", session.id);
+                    let frame = CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "pong timeout".into(),
+                    };
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                    break;
+                }
             }
         }
     });
 
     // Handle messages from the client
-    let receive_task = tokio::spawn(async move {
+    let receive_session = session.clone();
+    let mut receive_task = tokio::spawn(async move {
         while let Some(result) = receiver.next().await {
             match result {
-                Ok(axum::extract::ws::Message::Text(text)) => {
-                    info!("Received message: {}", text);
-
-                    // Here you could handle client requests
-                    // For example, client could request historical data
+                Ok(Message::Text(text)) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Subscribe { topic }) => {
+                        debug!("Session {} subscribed to {}", receive_session.id, topic);
+                        receive_session.subscribe(&topic);
+                    }
+                    Ok(ClientMessage::Unsubscribe { topic }) => {
+                        debug!("Session {} unsubscribed from {}", receive_session.id, topic);
+                        receive_session.unsubscribe(&topic);
+                    }
+                    Ok(ClientMessage::QuoteTokens { tokens }) => {
+                        debug!("Session {} quotes prices in {:?}", receive_session.id, tokens);
+                        receive_session.set_quote_tokens(tokens);
+                    }
+                    Err(e) => warn!("Ignoring message from session {}: {}", receive_session.id, e),
+                },
+                Ok(Message::Pong(_)) => {
+                    receive_session.record_pong();
                 }
-                Ok(axum::extract::ws::Message::Close(_)) => {
+                Ok(Message::Close(_)) => {
                     info!("Client initiated close");
                     break;
                 }
@@ -85,11 +292,18 @@ async fn handle_socket(websocket: axum::extract::ws::WebSocket, state: Simulatio
         }
     });
 
-    // Wait for either task to finish
+    // Wait for either task to finish, then tear down the other one
     tokio::select! {
-        _ = send_task => info!("Send task completed"),
-        _ = receive_task => info!("Receive task completed"),
+        _ = &mut send_task => {
+            info!("Send task completed");
+            receive_task.abort();
+        }
+        _ = &mut receive_task => {
+            info!("Receive task completed");
+            send_task.abort();
+        }
     }
 
-    info!("WebSocket connection closed");
+    info!("WebSocket connection closed (session {})", session.id);
+    drop(guard);
 }
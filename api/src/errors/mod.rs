@@ -3,9 +3,11 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use thiserror::Error;
 
+use crate::simulation::executor::ExecutorError;
+
 // Define Axum-specific error types
 #[derive(Error, Debug)]
 pub enum ApiError {
@@ -15,24 +17,105 @@ pub enum ApiError {
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    #[error("Service overloaded: {0}")]
+    Overloaded(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Invalid hop {hop} (pool {pool}): {message}")]
+    InvalidHop {
+        hop: usize,
+        pool: String,
+        message: String,
+    },
+
+    #[error("Hop {hop} (pool {pool}) is unavailable: {message}")]
+    PoolUnavailable {
+        hop: usize,
+        pool: String,
+        message: String,
+    },
+
+    #[error("Hop {hop} (pool {pool}): amount {requested} exceeds the pool limit of {max_sell}")]
+    ExceedsLimit {
+        hop: usize,
+        pool: String,
+        token: String,
+        requested: String,
+        max_sell: String,
+    },
+}
+
+impl From<ExecutorError> for ApiError {
+    fn from(error: ExecutorError) -> Self {
+        match error {
+            ExecutorError::Saturated => ApiError::Overloaded(error.to_string()),
+            ExecutorError::TimedOut => ApiError::Timeout(error.to_string()),
+            ExecutorError::Failed(_) => ApiError::SimulationError(error.to_string()),
+        }
+    }
 }
 
 // Implement IntoResponse for Axum
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
+        // Extra fields merged into the error body
+        let mut details = None;
         let (status, error_message) = match self {
             ApiError::SimulationError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
             ApiError::InvalidInput(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Overloaded(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+            ApiError::Timeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg),
+            ApiError::InvalidHop { hop, pool, message } => {
+                let msg = format!("Hop {} (pool {}): {}", hop, pool, message);
+                details = Some(json!({ "hop": hop, "pool": pool }));
+                (StatusCode::BAD_REQUEST, msg)
+            }
+            // Quarantined pools are released once they price again
+            ApiError::PoolUnavailable { hop, pool, message } => {
+                let msg = format!("Hop {} (pool {}) is unavailable: {}", hop, pool, message);
+                details = Some(json!({ "hop": hop, "pool": pool }));
+                (StatusCode::SERVICE_UNAVAILABLE, msg)
+            }
+            ApiError::ExceedsLimit {
+                hop,
+                pool,
+                token,
+                requested,
+                max_sell,
+            } => {
+                let msg = format!(
+                    "Hop {} (pool {}): amount {} exceeds the pool limit of {}",
+                    hop, pool, requested, max_sell
+                );
+                details = Some(json!({
+                    "hop": hop,
+                    "pool": pool,
+                    "token": token,
+                    "requested": requested,
+                    "max_sellable": max_sell,
+                }));
+                (StatusCode::UNPROCESSABLE_ENTITY, msg)
+            }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "success": false,
             "error": error_message
-        }));
+        });
+        if let (Value::Object(body), Some(Value::Object(details))) = (&mut body, details) {
+            body.extend(details);
+        }
 
-        (status, body).into_response()
+        (status, Json(body)).into_response()
     }
 }
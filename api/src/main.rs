@@ -3,15 +3,27 @@ mod errors;
 mod simulation;
 mod utils;
 
-use api::start_api_server;
+use api::{
+    connections::{ConnectionLimits, ConnectionRegistry},
+    start_api_server, AdminToken, AppState,
+};
 use clap::Parser;
 use dotenv::dotenv;
-use simulation::{state::SimulationState, start_simulation_processor};
-use std::{env, str::FromStr, time::Duration};
+use simulation::{
+    arbitrage::{ArbitrageScanner, ScannerConfig, MAX_CYCLE_LENGTH},
+    executor::{ExecutorConfig, SimulationExecutor},
+    history::PriceHistory,
+    participation::{GridPair, ParticipationTracker, TradeGrid},
+    sandbox::{SandboxConfig, SandboxStore},
+    pricing::{default_native_token, default_numeraire, PricingConfig},
+    state::{SimulationState, DEFAULT_HISTORY_DEPTH},
+    start_simulation_processor,
+};
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
 use tokio::sync::mpsc;
 use tycho_simulation::tycho_core::models::Chain;
 use utils::setup::setup_tracing;
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
 #[derive(Parser)]
 struct Cli {
@@ -30,6 +42,95 @@ struct Cli {
     /// Tycho server URL
     #[clap(long)]
     pub tycho_url: String,
+    /// Maximum number of concurrent websocket connections
+    #[clap(long, default_value = "1000")]
+    pub ws_max_connections: usize,
+    /// Maximum number of concurrent websocket connections from a single IP
+    #[clap(long, default_value = "20")]
+    pub ws_max_connections_per_ip: usize,
+    /// Count per-IP connections against the client IP in X-Forwarded-For.
+    /// Set this when running behind a proxy such as the ingress.
+    #[clap(long)]
+    pub trust_forwarded_for: bool,
+    /// Seconds between server-initiated websocket pings
+    #[clap(long, default_value = "30")]
+    pub ws_ping_interval_secs: u64,
+    /// Seconds a client has to answer a ping before it is disconnected
+    #[clap(long, default_value = "10")]
+    pub ws_pong_timeout_secs: u64,
+    /// Number of recent block snapshots kept to roll back to on a chain reorg
+    #[clap(long, default_value_t = DEFAULT_HISTORY_DEPTH)]
+    pub reorg_history_depth: usize,
+    /// Maximum number of request and background simulations running at once
+    #[clap(long, default_value = "8")]
+    pub sim_max_concurrency: usize,
+    /// Workers reserved for computing the spot prices of new blocks
+    #[clap(long, default_value = "4")]
+    pub sim_ingest_concurrency: usize,
+    /// Milliseconds a request waits for a free simulation worker before getting a 503
+    #[clap(long, default_value = "500")]
+    pub sim_queue_timeout_ms: u64,
+    /// Milliseconds a simulation may run before the request gets a 504
+    #[clap(long, default_value = "5000")]
+    pub sim_timeout_ms: u64,
+    /// Gas price in gwei used to value gas in token terms, gas costs are omitted without it
+    #[clap(long)]
+    pub gas_price_gwei: Option<f64>,
+    /// Wrapped native token gas is valued through, defaults to the chain's WETH
+    #[clap(long)]
+    pub native_token: Option<String>,
+    /// Token prices and gas costs are reported in, defaults to the chain's USDC
+    #[clap(long)]
+    pub numeraire: Option<String>,
+    /// Maximum number of simulation sessions open at once
+    #[clap(long, default_value = "100")]
+    pub max_sessions: usize,
+    /// Seconds a simulation session may sit unused before it is discarded
+    #[clap(long, default_value = "600")]
+    pub session_idle_timeout_secs: u64,
+    /// Token pairs for the background participation analysis, as sell:buy
+    /// addresses separated by commas. The analysis is off when empty.
+    #[clap(long, value_delimiter = ',')]
+    pub participation_pairs: Vec<String>,
+    /// Run the participation analysis every this many blocks
+    #[clap(long, default_value = "10")]
+    pub participation_every_blocks: u64,
+    /// Smallest trade of the participation grid, in numeraire units
+    #[clap(long, default_value = "100")]
+    pub participation_min_size: f64,
+    /// Largest trade of the participation grid, in numeraire units
+    #[clap(long, default_value = "1000000")]
+    pub participation_max_size: f64,
+    /// Number of trade sizes in the participation grid
+    #[clap(long, default_value = "5")]
+    pub participation_steps: usize,
+    /// Disable the per-block arbitrage and price deviation scanner
+    #[clap(long)]
+    pub no_arbitrage_scanner: bool,
+    /// Spread between pools of a pair reported as a deviation, in basis points
+    #[clap(long, default_value = "50")]
+    pub arbitrage_deviation_bps: f64,
+    /// Longest cycle the arbitrage scanner searches for, at most 4
+    #[clap(long, default_value = "3")]
+    pub arbitrage_max_cycle_length: usize,
+    /// Spot profit a cycle needs before it is simulated, in basis points
+    #[clap(long, default_value = "5")]
+    pub arbitrage_min_spot_profit_bps: f64,
+    /// Size arbitrage cycles are simulated with, in numeraire units
+    #[clap(long, default_value = "1000")]
+    pub arbitrage_probe_size: f64,
+    /// Most arbitrage cycles simulated per block
+    #[clap(long, default_value = "20")]
+    pub arbitrage_max_candidates: usize,
+    /// Spot price points kept per pool, 0 disables the history
+    #[clap(long, default_value = "1000")]
+    pub history_points: usize,
+    /// File the price history is loaded from on startup and periodically saved to
+    #[clap(long)]
+    pub history_file: Option<PathBuf>,
+    /// Estimate pool TVL for the history every this many blocks, 0 disables it
+    #[clap(long, default_value = "0")]
+    pub history_tvl_every_blocks: u64,
 }
 
 #[tokio::main]
@@ -66,14 +167,115 @@ async fn main() -> anyhow::Result<()> {
     let tycho_api_key = env::var("TYCHO_API_KEY").unwrap_or_else(|_| panic!("TYCHO_API_KEY environment variable not set"));
 
     // Create shared state for the simulation
-    let simulation_state = SimulationState::new();
+    let executor = SimulationExecutor::new(ExecutorConfig {
+        max_concurrency: cli.sim_max_concurrency,
+        ingest_concurrency: cli.sim_ingest_concurrency,
+        queue_timeout: Duration::from_millis(cli.sim_queue_timeout_ms),
+        job_timeout: Duration::from_millis(cli.sim_timeout_ms),
+    });
+    let pricing = PricingConfig {
+        gas_price_wei: cli.gas_price_gwei.map(|gwei| gwei * 1e9),
+        native_token: cli
+            .native_token
+            .as_deref()
+            .or(default_native_token(chain))
+            .unwrap_or_else(|| panic!("--native-token is required for chain {}", cli.chain))
+            .to_lowercase(),
+        numeraire: cli
+            .numeraire
+            .as_deref()
+            .or(default_numeraire(chain))
+            .unwrap_or_else(|| panic!("--numeraire is required for chain {}", cli.chain))
+            .to_lowercase(),
+    };
+    let simulation_state = SimulationState::new(cli.reorg_history_depth, executor, pricing);
     info!("Created simulation state");
 
     // Create initial channel for API server
     let (api_tx, _api_rx) = mpsc::channel(32);
     
+    let connection_limits = ConnectionLimits {
+        max_connections: cli.ws_max_connections,
+        max_connections_per_ip: cli.ws_max_connections_per_ip,
+        ping_interval: Duration::from_secs(cli.ws_ping_interval_secs),
+        pong_timeout: Duration::from_secs(cli.ws_pong_timeout_secs),
+        trust_forwarded_for: cli.trust_forwarded_for,
+    };
+
+    let participation = ParticipationTracker::default();
+    if !cli.participation_pairs.is_empty() {
+        let pairs = cli
+            .participation_pairs
+            .iter()
+            .map(|pair| match pair.split_once(':') {
+                Some((sell, buy)) => GridPair {
+                    sell_token: sell.to_string(),
+                    buy_token: buy.to_string(),
+                },
+                None => panic!("Invalid participation pair {}, expected sell:buy", pair),
+            })
+            .collect();
+        let grid = TradeGrid {
+            pairs,
+            min_size: cli.participation_min_size,
+            max_size: cli.participation_max_size,
+            steps: cli.participation_steps,
+        };
+        if let Err(e) = grid.validate() {
+            panic!("Invalid participation grid: {}", e);
+        }
+        participation.spawn(simulation_state.clone(), grid, cli.participation_every_blocks);
+    }
+
+    let arbitrage = ArbitrageScanner::new();
+    if !cli.no_arbitrage_scanner {
+        if cli.arbitrage_max_cycle_length > MAX_CYCLE_LENGTH {
+            warn!(
+                "--arbitrage-max-cycle-length {} is above the maximum, using {}",
+                cli.arbitrage_max_cycle_length, MAX_CYCLE_LENGTH
+            );
+        }
+        arbitrage.spawn(
+            simulation_state.clone(),
+            ScannerConfig {
+                deviation_threshold_bps: cli.arbitrage_deviation_bps,
+                max_cycle_length: cli.arbitrage_max_cycle_length.min(MAX_CYCLE_LENGTH),
+                min_spot_profit_bps: cli.arbitrage_min_spot_profit_bps,
+                probe_size: cli.arbitrage_probe_size,
+                max_candidates: cli.arbitrage_max_candidates,
+            },
+        );
+    }
+
+    let history = PriceHistory::new(cli.history_points);
+    if cli.history_points > 0 {
+        if let Some(path) = &cli.history_file {
+            if let Err(e) = history.load(path).await {
+                warn!("Failed to load price history from {}: {}", path.display(), e);
+            }
+        }
+        history.spawn(
+            simulation_state.clone(),
+            cli.history_file.clone(),
+            cli.history_tvl_every_blocks,
+        );
+    }
+
     // Start API server (runs forever, no retry)
-    let api_handle = start_api_server(cli.port, simulation_state.clone(), api_tx.clone());
+    let app_state = AppState {
+        simulation: simulation_state.clone(),
+        connections: ConnectionRegistry::new(connection_limits),
+        sandboxes: SandboxStore::new(SandboxConfig {
+            max_sandboxes: cli.max_sessions,
+            idle_timeout: Duration::from_secs(cli.session_idle_timeout_secs),
+        }),
+        participation,
+        arbitrage,
+        history,
+        // Kept out of the CLI args so it doesn't show up in the process list
+        admin_token: AdminToken::new(env::var("ADMIN_TOKEN").ok()),
+    };
+    let api_handle = start_api_server(cli.port, app_state, api_tx.clone());
     info!("API server started on port {}", cli.port);
     
     // Restart loop only for simulation processor
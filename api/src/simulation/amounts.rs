@@ -0,0 +1,162 @@
+use num_bigint::BigUint;
+use serde::Deserialize;
+
+/// Largest exponent accepted in scientific notation, well above any token's decimals
+const MAX_EXPONENT: i64 = 96;
+
+/// Unit an amount is expressed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountUnit {
+    /// Whole tokens, e.g. "1.5" WETH
+    #[default]
+    Human,
+    /// Smallest token units, e.g. "1500000000000000000" wei
+    Raw,
+}
+
+/// Parse a non-negative decimal amount, optionally in scientific notation
+/// ("1.5", "2e-3", "1E18"), into raw token units for a token with `decimals`.
+/// Raw amounts are parsed the same way with zero decimals, so they must come
+/// out as whole numbers.
+pub fn parse_amount(input: &str, unit: AmountUnit, decimals: u32) -> Result<BigUint, String> {
+    let decimals = match unit {
+        AmountUnit::Human => decimals,
+        AmountUnit::Raw => 0,
+    };
+    let input = input.trim();
+    let (mantissa, exponent) = match input.find(['e', 'E']) {
+        Some(pos) => {
+            let exponent = input[pos + 1..]
+                .parse::<i64>()
+                .map_err(|_| format!("Invalid exponent in amount {}", input))?;
+            (&input[..pos], exponent)
+        }
+        None => (input, 0),
+    };
+    if exponent.abs() > MAX_EXPONENT {
+        return Err(format!("Exponent out of range in amount {}", input));
+    }
+
+    let mantissa = mantissa.strip_prefix('+').unwrap_or(mantissa);
+    if mantissa.starts_with('-') {
+        return Err(format!("Amount must not be negative: {}", input));
+    }
+    let (integer_part, fraction_part) = match mantissa.split_once('.') {
+        Some((integer_part, fraction_part)) => (integer_part, fraction_part),
+        None => (mantissa, ""),
+    };
+    let digits = format!("{}{}", integer_part, fraction_part);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("Invalid amount format: {}", input));
+    }
+
+    // digits * 10^scale is the amount in raw units
+    let scale = exponent + decimals as i64 - fraction_part.len() as i64;
+    if scale >= 0 {
+        let value = BigUint::parse_bytes(digits.as_bytes(), 10)
+            .ok_or_else(|| format!("Invalid amount format: {}", input))?;
+        return Ok(value * BigUint::from(10u32).pow(scale as u32));
+    }
+
+    // Anything below one raw unit has to be zeros
+    let cut = digits.len().saturating_sub(scale.unsigned_abs() as usize);
+    if digits[cut..].bytes().any(|b| b != b'0') {
+        return Err(match unit {
+            AmountUnit::Human => format!(
+                "Too many decimal places. Token supports {} decimals",
+                decimals
+            ),
+            AmountUnit::Raw => format!("Raw amount must be a whole number: {}", input),
+        });
+    }
+    let whole = &digits[..cut];
+    if whole.is_empty() {
+        return Ok(BigUint::from(0u32));
+    }
+    BigUint::parse_bytes(whole.as_bytes(), 10)
+        .ok_or_else(|| format!("Invalid amount format: {}", input))
+}
+
+/// Format raw token units as a human readable decimal string
+pub fn format_amount(amount: &BigUint, decimals: u32) -> String {
+    let divisor = BigUint::from(10u32).pow(decimals);
+    let integer_part = amount / &divisor;
+    let remainder = amount % &divisor;
+
+    if remainder == BigUint::from(0u32) {
+        return integer_part.to_string();
+    }
+    // Pad remainder with leading zeros if needed
+    let remainder_str = format!("{:0>width$}", remainder.to_string(), width = decimals as usize);
+    // Trim trailing zeros
+    let trimmed = remainder_str.trim_end_matches('0');
+    if trimmed.is_empty() {
+        integer_part.to_string()
+    } else {
+        format!("{}.{}", integer_part, trimmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn human(input: &str, decimals: u32) -> Result<BigUint, String> {
+        parse_amount(input, AmountUnit::Human, decimals)
+    }
+
+    #[test]
+    fn round_trips_through_format() {
+        for (input, decimals) in [("1.5", 18), ("0.000001", 6), ("42", 0), ("123456.789", 9)] {
+            let raw = human(input, decimals).unwrap();
+            assert_eq!(format_amount(&raw, decimals), input);
+        }
+    }
+
+    #[test]
+    fn parses_scientific_notation() {
+        assert_eq!(human("1.5e-3", 18).unwrap(), BigUint::from(1_500_000_000_000_000u64));
+        assert_eq!(human("2E6", 6).unwrap(), BigUint::from(2_000_000_000_000u64));
+        assert_eq!(
+            parse_amount("1e18", AmountUnit::Raw, 6).unwrap(),
+            BigUint::from(10u32).pow(18)
+        );
+    }
+
+    #[test]
+    fn raw_amounts_ignore_decimals() {
+        assert_eq!(parse_amount("1000", AmountUnit::Raw, 18).unwrap(), BigUint::from(1000u32));
+        assert_eq!(parse_amount("1000.00", AmountUnit::Raw, 18).unwrap(), BigUint::from(1000u32));
+    }
+
+    #[test]
+    fn rejects_raw_amounts_with_a_fraction() {
+        let err = parse_amount("1.5", AmountUnit::Raw, 18).unwrap_err();
+        assert!(err.contains("whole number"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_too_many_decimals() {
+        let err = human("1.0000001", 6).unwrap_err();
+        assert!(err.contains("Too many decimal places"), "{}", err);
+        // Trailing zeros past the token's precision are fine
+        assert_eq!(human("1.0000000", 6).unwrap(), BigUint::from(1_000_000u32));
+    }
+
+    #[test]
+    fn rejects_negative_and_malformed_amounts() {
+        assert!(human("-1", 18).unwrap_err().contains("negative"));
+        for input in ["", "   ", ".", "e5", "1.2.3", "abc", "1e", "1e1000"] {
+            assert!(human(input, 18).is_err(), "accepted {:?}", input);
+        }
+    }
+
+    #[test]
+    fn formats_whole_and_fractional_amounts() {
+        assert_eq!(format_amount(&BigUint::from(0u32), 18), "0");
+        assert_eq!(format_amount(&BigUint::from(1_000_000u32), 6), "1");
+        assert_eq!(format_amount(&BigUint::from(1_050_000u32), 6), "1.05");
+        assert_eq!(format_amount(&BigUint::from(1u32), 6), "0.000001");
+    }
+}
@@ -0,0 +1,374 @@
+use num_bigint::{BigInt, BigUint};
+use num_traits::FromPrimitive;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use super::executor::CancelToken;
+use super::pricing::PricingConfig;
+use super::router::{candidate_cycles, RouteOptions};
+use super::state::{BlockSnapshot, SimulationState};
+use super::swap::{simulate_path, to_units, LimitMode};
+
+/// Most pair deviations kept in a report
+const MAX_DEVIATIONS: usize = 100;
+
+/// Longest cycle the scanner searches for, the search grows exponentially
+/// with the length
+pub const MAX_CYCLE_LENGTH: usize = 4;
+
+/// Thresholds of the arbitrage scanner
+#[derive(Debug, Clone, Copy)]
+pub struct ScannerConfig {
+    /// Spread between pools of a pair worth reporting, in basis points
+    pub deviation_threshold_bps: f64,
+    /// Longest cycle searched for
+    pub max_cycle_length: usize,
+    /// Spot profit a cycle needs before it is simulated, in basis points
+    pub min_spot_profit_bps: f64,
+    /// Size cycles are simulated with, in numeraire units
+    pub probe_size: f64,
+    /// Most cycles simulated per block
+    pub max_candidates: usize,
+}
+
+/// Pools of the same pair quoting prices further apart than the threshold
+#[derive(Debug, Clone, Serialize)]
+pub struct PairDeviation {
+    pub base: String,
+    pub quote: String,
+    pub pools: usize,
+    /// Pool quoting the lowest and the highest price, in quote per base
+    pub low_pool: String,
+    pub low_price: f64,
+    pub high_pool: String,
+    pub high_price: f64,
+    pub spread_bps: f64,
+}
+
+/// A cycle that returns more than it starts with at spot prices, checked by
+/// simulating a probe trade through it
+#[derive(Debug, Clone, Serialize)]
+pub struct Opportunity {
+    /// Token the cycle starts and ends in
+    pub token: String,
+    pub pools: Vec<String>,
+    /// Tokens visited, starting and ending with `token`
+    pub path: Vec<String>,
+    pub spot_rate: f64,
+    pub amount_in: String,
+    pub amount_out: String,
+    pub gas: String,
+    /// Profit in base units of `token`, before and after gas
+    pub profit: String,
+    pub net_profit: Option<String>,
+    pub profit_bps: f64,
+    /// Whether the probe trade made money after gas, or before gas when gas
+    /// can't be priced
+    pub profitable: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanReport {
+    pub block_number: u64,
+    pub generated_at: u64,
+    pub deviations: Vec<PairDeviation>,
+    /// Verified cycles, most profitable first
+    pub opportunities: Vec<Opportunity>,
+    /// Cycles that looked profitable at spot prices and were simulated
+    pub cycles_checked: usize,
+}
+
+/// Pairs whose pools quote prices more than the threshold apart
+fn find_deviations(snapshot: &BlockSnapshot, threshold_bps: f64) -> Vec<PairDeviation> {
+    let mut by_pair: HashMap<(&str, &str), Vec<(&String, f64)>> = HashMap::new();
+    for (pool, pairs) in &snapshot.pair_prices {
+        if snapshot.quarantined.contains_key(pool) {
+            continue;
+        }
+        for pair in pairs {
+            if !(pair.price.is_finite() && pair.price > 0.0) {
+                continue;
+            }
+            // Orient every pair the same way so pools can be compared
+            if pair.base < pair.quote {
+                by_pair
+                    .entry((pair.base.as_str(), pair.quote.as_str()))
+                    .or_default()
+                    .push((pool, pair.price));
+            } else {
+                by_pair
+                    .entry((pair.quote.as_str(), pair.base.as_str()))
+                    .or_default()
+                    .push((pool, 1.0 / pair.price));
+            }
+        }
+    }
+
+    let mut deviations: Vec<PairDeviation> = by_pair
+        .into_iter()
+        .filter(|(_, pools)| pools.len() > 1)
+        .filter_map(|((base, quote), pools)| {
+            let low = pools.iter().min_by(|a, b| a.1.total_cmp(&b.1))?;
+            let high = pools.iter().max_by(|a, b| a.1.total_cmp(&b.1))?;
+            let spread_bps = (high.1 / low.1 - 1.0) * 10_000.0;
+            (spread_bps > threshold_bps).then(|| PairDeviation {
+                base: base.to_string(),
+                quote: quote.to_string(),
+                pools: pools.len(),
+                low_pool: low.0.clone(),
+                low_price: low.1,
+                high_pool: high.0.clone(),
+                high_price: high.1,
+                spread_bps,
+            })
+        })
+        .collect();
+    deviations.sort_by(|a, b| b.spread_bps.total_cmp(&a.spread_bps));
+    deviations.truncate(MAX_DEVIATIONS);
+    deviations
+}
+
+/// Scan one block for deviating pairs and profitable cycles. Stops
+/// simulating once `cancel` is set. Meant to run on a blocking worker.
+pub fn scan(
+    snapshot: &BlockSnapshot,
+    pricing: &PricingConfig,
+    config: &ScannerConfig,
+    cancel: &CancelToken,
+) -> ScanReport {
+    let deviations = find_deviations(snapshot, config.deviation_threshold_bps);
+
+    let options = RouteOptions {
+        max_candidates: config.max_candidates,
+        // Two pools per pair so cycles between pools of the same pair are found
+        pools_per_pair: 2,
+        ..RouteOptions::default()
+    };
+    let min_rate = 1.0 + config.min_spot_profit_bps / 10_000.0;
+    let max_length = config.max_cycle_length.min(MAX_CYCLE_LENGTH);
+    let cycles = candidate_cycles(snapshot, max_length, min_rate, options);
+    let cycles_checked = cycles.len();
+
+    let mut opportunities = Vec::new();
+    for (spot_rate, cycle) in cycles {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let token = &cycle[0].token_in;
+        let address = token.address.to_string();
+        let Some(amount_in) = snapshot
+            .token_prices
+            .get(&address)
+            .and_then(|price| {
+                BigUint::from_f64(config.probe_size / price * 10f64.powi(token.decimals as i32))
+            })
+            .filter(|amount| *amount > BigUint::from(0u32))
+        else {
+            continue;
+        };
+        // A capped hop would drop part of the input while profit is still
+        // measured against all of it, so a probe too large for a pool is skipped
+        let outcome = match simulate_path(snapshot, &cycle, amount_in.clone(), LimitMode::Enforce, cancel) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                debug!("Cycle from {} failed to simulate: {}", address, e);
+                continue;
+            }
+        };
+
+        let profit = BigInt::from(outcome.amount_out.clone()) - BigInt::from(outcome.amount_in.clone());
+        let gas_cost = pricing
+            .gas_cost(snapshot, &outcome.total_gas, token)
+            .and_then(|cost| cost.output_token_raw(token.decimals as u32));
+        let net_profit = gas_cost.map(|cost| &profit - BigInt::from(cost));
+        let profitable = match &net_profit {
+            Some(net) => *net > BigInt::from(0),
+            None => profit > BigInt::from(0),
+        };
+        let decimals = token.decimals as u32;
+        let profit_bps =
+            (to_units(&outcome.amount_out, decimals) / to_units(&outcome.amount_in, decimals) - 1.0)
+                * 10_000.0;
+
+        let mut path = vec![address.clone()];
+        path.extend(cycle.iter().map(|hop| hop.token_out.address.to_string()));
+        opportunities.push(Opportunity {
+            token: address,
+            pools: cycle.iter().map(|hop| hop.pool.clone()).collect(),
+            path,
+            spot_rate,
+            amount_in: outcome.amount_in.to_string(),
+            amount_out: outcome.amount_out.to_string(),
+            gas: outcome.total_gas.to_string(),
+            profit: profit.to_string(),
+            net_profit: net_profit.map(|net| net.to_string()),
+            profit_bps,
+            profitable,
+        });
+    }
+    opportunities.sort_by(|a, b| b.profit_bps.total_cmp(&a.profit_bps));
+
+    ScanReport {
+        block_number: snapshot.block_number,
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        deviations,
+        opportunities,
+        cycles_checked,
+    }
+}
+
+/// Runs the scanner on every block, keeps the latest report and pushes it to
+/// websocket subscribers
+#[derive(Debug, Clone)]
+pub struct ArbitrageScanner {
+    latest: Arc<RwLock<Option<Arc<ScanReport>>>>,
+    reports: broadcast::Sender<Arc<ScanReport>>,
+    // Set once the scanner is spawned, it stays off with --no-arbitrage-scanner
+    enabled: Arc<AtomicBool>,
+}
+
+impl ArbitrageScanner {
+    pub fn new() -> Self {
+        // Subscribers only care about the latest report, a short buffer is enough
+        let (reports, _) = broadcast::channel(4);
+        ArbitrageScanner {
+            latest: Arc::new(RwLock::new(None)),
+            reports,
+            enabled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn latest(&self) -> Option<Arc<ScanReport>> {
+        self.latest.read().unwrap().clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ScanReport>> {
+        self.reports.subscribe()
+    }
+
+    pub fn spawn(&self, state: SimulationState, config: ScannerConfig) {
+        self.enabled.store(true, Ordering::Relaxed);
+        let scanner = self.clone();
+        tokio::spawn(async move {
+            let subscription = state.subscribe_to_updates();
+            let mut next_block_ready = false;
+            loop {
+                // Blocks that arrive while a scan runs are coalesced, so a
+                // slow scan skips ahead to the latest block
+                if !next_block_ready {
+                    subscription.outbox().recv().await;
+                }
+                next_block_ready = false;
+                let snapshot = state.snapshot();
+                let block_number = snapshot.block_number;
+                let pricing = state.pricing().clone();
+                let cancel = CancelToken::default();
+                let job_cancel = cancel.clone();
+                let job = state
+                    .executor()
+                    .run_to_completion(move || scan(&snapshot, &pricing, &config, &job_cancel));
+                tokio::pin!(job);
+                let result = tokio::select! {
+                    result = &mut job => result,
+                    // A newer block makes this scan stale, stop it so scans
+                    // never overlap
+                    _ = subscription.outbox().recv() => {
+                        next_block_ready = true;
+                        cancel.cancel();
+                        job.await
+                    }
+                };
+                if cancel.is_cancelled() {
+                    debug!("Arbitrage scan of block {} superseded by a newer block", block_number);
+                    continue;
+                }
+                let report = match result {
+                    Ok(report) => Arc::new(report),
+                    Err(e) => {
+                        warn!("Arbitrage scan failed: {}", e);
+                        continue;
+                    }
+                };
+                let profitable = report.opportunities.iter().filter(|o| o.profitable).count();
+                if profitable > 0 {
+                    info!(
+                        "Block {}: {} profitable cycles, {} deviating pairs",
+                        report.block_number,
+                        profitable,
+                        report.deviations.len()
+                    );
+                }
+                *scanner.latest.write().unwrap() = Some(report.clone());
+                // No receivers just means nobody is subscribed
+                let _ = scanner.reports.send(report);
+            }
+        });
+    }
+}
+
+impl Default for ArbitrageScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::fixtures::{add_pool, address, token};
+    use crate::simulation::quarantine::QuarantineEntry;
+
+    #[test]
+    fn reports_pairs_spread_past_the_threshold() {
+        let (a, b) = (token(1, 18), token(2, 6));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "cheap", &a, &b, 100.0);
+        add_pool(&mut snapshot, "dear", &a, &b, 101.0);
+        // Quoted the other way around
+        add_pool(&mut snapshot, "inverse", &b, &a, 1.0 / 100.5);
+
+        let deviations = find_deviations(&snapshot, 50.0);
+        assert_eq!(deviations.len(), 1);
+        let deviation = &deviations[0];
+        assert_eq!(deviation.base, address(&a));
+        assert_eq!(deviation.quote, address(&b));
+        assert_eq!(deviation.pools, 3);
+        assert_eq!(deviation.low_pool, "cheap");
+        assert_eq!(deviation.high_pool, "dear");
+        assert!((deviation.spread_bps - 100.0).abs() < 1e-6);
+
+        assert!(find_deviations(&snapshot, 150.0).is_empty());
+    }
+
+    #[test]
+    fn ignores_single_pool_pairs_and_quarantined_pools() {
+        let (a, b, c) = (token(1, 18), token(2, 6), token(3, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "ab", &a, &b, 100.0);
+        add_pool(&mut snapshot, "ab_broken", &a, &b, 200.0);
+        add_pool(&mut snapshot, "bc", &b, &c, 1.0);
+        add_pool(&mut snapshot, "bc_zero", &b, &c, 0.0);
+        snapshot.quarantined.insert(
+            "ab_broken".to_string(),
+            QuarantineEntry::new("ab_broken", "uniswap_v2", "panicked".to_string(), 1),
+        );
+
+        assert!(find_deviations(&snapshot, 0.0).is_empty());
+    }
+}
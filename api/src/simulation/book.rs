@@ -0,0 +1,303 @@
+use num_bigint::BigUint;
+use serde::Serialize;
+use tycho_simulation::tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim};
+
+use crate::errors::ApiError;
+
+use super::curve::log_amounts;
+use super::executor::CancelToken;
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+use super::swap::{find_token, to_units};
+
+/// Slack, in fractions of a level, within which a price counts as on the edge
+const LEVEL_EDGE_TOLERANCE: f64 = 1e-9;
+
+/// How a book is bucketed
+#[derive(Debug, Clone, Copy)]
+pub struct BookOptions {
+    /// Price levels per side
+    pub levels: usize,
+    /// Width of each level, in basis points of the mid price
+    pub step_bps: f64,
+    /// Sizes sampled per pool and side
+    pub samples: usize,
+}
+
+/// Liquidity available within one price level, all amounts in whole tokens
+#[derive(Debug, Clone, Serialize)]
+pub struct BookLevel {
+    /// Worst price of the level, in quote per base
+    pub price: f64,
+    pub base_amount: f64,
+    pub quote_amount: f64,
+    pub cumulative_base: f64,
+    pub cumulative_quote: f64,
+    /// Pools contributing to the level
+    pub pools: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderBook {
+    pub block_number: u64,
+    pub base_token: String,
+    pub quote_token: String,
+    /// Median spot price across pools, in quote per base
+    pub mid_price: f64,
+    pub step_bps: f64,
+    /// Pools that trade the pair, and those that failed to simulate
+    pub pools: usize,
+    pub failed_pools: Vec<String>,
+    /// Buying base, best price first
+    pub bids: Vec<BookLevel>,
+    /// Selling base, best price first
+    pub asks: Vec<BookLevel>,
+}
+
+/// A slice of a pool's curve: trading `base` of the base token at `price`
+struct Segment {
+    price: f64,
+    base: f64,
+    pool: usize,
+}
+
+/// Marginal price segments of a pool when selling `sell` for `buy`, between
+/// consecutive sampled input sizes up to the pool's limit
+fn pool_segments(
+    state: &dyn ProtocolSim,
+    sell: &Token,
+    buy: &Token,
+    samples: usize,
+) -> Result<Vec<(f64, f64)>, String> {
+    let (max_sell, _) = isolate(|| state.get_limits(sell.address.clone(), buy.address.clone()))?;
+    let mut segments = Vec::new();
+    let mut previous_in = BigUint::from(0u32);
+    let mut previous_out = BigUint::from(0u32);
+    for amount_in in log_amounts(&max_sell, samples) {
+        // The deepest sizes may fail, the pool's depth just ends there
+        let Ok(result) = isolate(|| state.get_amount_out(amount_in.clone(), sell, buy)) else {
+            break;
+        };
+        if result.amount <= previous_out {
+            break;
+        }
+        let sold = to_units(&(&amount_in - &previous_in), sell.decimals as u32);
+        let bought = to_units(&(&result.amount - &previous_out), buy.decimals as u32);
+        segments.push((sold, bought));
+        previous_in = amount_in;
+        previous_out = result.amount;
+    }
+    Ok(segments)
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    Some(values[values.len() / 2])
+}
+
+/// Bucket segments into levels moving away from the mid price
+fn build_levels(mut segments: Vec<Segment>, mid: f64, options: BookOptions, bids: bool) -> Vec<BookLevel> {
+    let step = options.step_bps / 10_000.0;
+    let mut levels: Vec<(f64, f64, Vec<usize>)> = vec![(0.0, 0.0, Vec::new()); options.levels];
+    // Best price first, so the level price ends up as the worst one filled
+    segments.sort_by(|a, b| {
+        if bids {
+            b.price.total_cmp(&a.price)
+        } else {
+            a.price.total_cmp(&b.price)
+        }
+    });
+    let mut prices = vec![0.0; options.levels];
+    for segment in segments {
+        let distance = if bids {
+            (mid - segment.price) / mid
+        } else {
+            (segment.price - mid) / mid
+        };
+        // A price on a level's edge opens the next level, whichever way the
+        // division rounds
+        let index = (distance.max(0.0) / step + LEVEL_EDGE_TOLERANCE).floor() as usize;
+        let Some(level) = levels.get_mut(index) else {
+            continue;
+        };
+        level.0 += segment.base;
+        level.1 += segment.base * segment.price;
+        if !level.2.contains(&segment.pool) {
+            level.2.push(segment.pool);
+        }
+        prices[index] = segment.price;
+    }
+
+    let mut cumulative_base = 0.0;
+    let mut cumulative_quote = 0.0;
+    levels
+        .into_iter()
+        .zip(prices)
+        .filter(|((base, _, _), _)| *base > 0.0)
+        .map(|((base_amount, quote_amount, pools), price)| {
+            cumulative_base += base_amount;
+            cumulative_quote += quote_amount;
+            BookLevel {
+                price,
+                base_amount,
+                quote_amount,
+                cumulative_base,
+                cumulative_quote,
+                pools: pools.len(),
+            }
+        })
+        .collect()
+}
+
+/// Aggregate the depth of every pool trading `base`/`quote` into one book.
+/// Meant to run on a blocking worker.
+pub fn order_book(
+    snapshot: &BlockSnapshot,
+    base: &str,
+    quote: &str,
+    options: BookOptions,
+    cancel: &CancelToken,
+) -> Result<OrderBook, ApiError> {
+    if base.eq_ignore_ascii_case(quote) {
+        return Err(ApiError::InvalidInput("Base and quote token must differ".to_string()));
+    }
+    let pools: Vec<(&String, &Token, &Token)> = snapshot
+        .components
+        .iter()
+        .filter(|(id, _)| !snapshot.quarantined.contains_key(*id))
+        .filter_map(|(id, component)| {
+            Some((id, find_token(component, base)?, find_token(component, quote)?))
+        })
+        .collect();
+    if pools.is_empty() {
+        return Err(ApiError::NotFound(format!("No pools trade {} / {}", base, quote)));
+    }
+
+    let mut bid_segments = Vec::new();
+    let mut ask_segments = Vec::new();
+    let mut spot_prices = Vec::new();
+    let mut failed_pools = Vec::new();
+    for (index, (id, base_token, quote_token)) in pools.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(ApiError::Timeout(format!("Order book for {} / {} abandoned", base, quote)));
+        }
+        let Some(state) = snapshot.states.get(*id) else {
+            continue;
+        };
+        if let Ok(price) = isolate(|| state.spot_price(base_token, quote_token)) {
+            spot_prices.push(price);
+        }
+        // Pool takes base and pays quote: someone bidding for base
+        let bids = pool_segments(state.as_ref(), base_token, quote_token, options.samples);
+        // Pool takes quote and pays base: someone offering base
+        let asks = pool_segments(state.as_ref(), quote_token, base_token, options.samples);
+        match (bids, asks) {
+            (Ok(bids), Ok(asks)) => {
+                bid_segments.extend(bids.into_iter().map(|(sold, bought)| Segment {
+                    price: bought / sold,
+                    base: sold,
+                    pool: index,
+                }));
+                ask_segments.extend(asks.into_iter().map(|(sold, bought)| Segment {
+                    price: sold / bought,
+                    base: bought,
+                    pool: index,
+                }));
+            }
+            _ => failed_pools.push(id.to_string()),
+        }
+    }
+
+    let mid_price = median(&mut spot_prices).ok_or_else(|| {
+        ApiError::SimulationError(format!("No pool trading {} / {} could be priced", base, quote))
+    })?;
+    let is_valid = |segment: &Segment| segment.price.is_finite() && segment.price > 0.0;
+    bid_segments.retain(is_valid);
+    ask_segments.retain(is_valid);
+
+    Ok(OrderBook {
+        block_number: snapshot.block_number,
+        base_token: base.to_lowercase(),
+        quote_token: quote.to_lowercase(),
+        mid_price,
+        step_bps: options.step_bps,
+        pools: pools.len(),
+        failed_pools,
+        bids: build_levels(bid_segments, mid_price, options, true),
+        asks: build_levels(ask_segments, mid_price, options, false),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(levels: usize) -> BookOptions {
+        BookOptions {
+            levels,
+            step_bps: 10.0,
+            samples: 8,
+        }
+    }
+
+    fn segment(price: f64, base: f64, pool: usize) -> Segment {
+        Segment { price, base, pool }
+    }
+
+    fn prices(levels: &[BookLevel]) -> Vec<f64> {
+        levels.iter().map(|level| level.price).collect()
+    }
+
+    #[test]
+    fn opens_a_new_level_on_each_edge() {
+        // Steps of 10 bps around 100: 100.1 and 99.9 are exactly one level out
+        let asks = vec![
+            segment(100.05, 1.0, 0),
+            segment(100.1, 1.0, 0),
+            segment(100.2, 1.0, 1),
+        ];
+        let levels = build_levels(asks, 100.0, options(5), false);
+        assert_eq!(prices(&levels), vec![100.05, 100.1, 100.2]);
+
+        let bids = vec![segment(99.95, 1.0, 0), segment(99.9, 1.0, 0), segment(99.8, 1.0, 1)];
+        let levels = build_levels(bids, 100.0, options(5), true);
+        assert_eq!(prices(&levels), vec![99.95, 99.9, 99.8]);
+    }
+
+    #[test]
+    fn prices_a_level_at_its_worst_fill() {
+        // Shuffled, and one ask below the mid which still counts as the best level
+        let asks = vec![
+            segment(100.08, 2.0, 1),
+            segment(99.99, 1.0, 0),
+            segment(100.02, 1.0, 0),
+            segment(100.15, 4.0, 1),
+        ];
+        let levels = build_levels(asks, 100.0, options(5), false);
+        assert_eq!(levels.len(), 2);
+
+        let first = &levels[0];
+        assert_eq!(first.price, 100.08);
+        assert_eq!(first.base_amount, 4.0);
+        assert!((first.quote_amount - (99.99 + 100.02 + 200.16)).abs() < 1e-9);
+        assert_eq!(first.pools, 2);
+
+        let second = &levels[1];
+        assert_eq!(second.price, 100.15);
+        assert_eq!(second.cumulative_base, 8.0);
+        assert!((second.cumulative_quote - (first.quote_amount + 400.6)).abs() < 1e-9);
+        assert_eq!(second.pools, 1);
+    }
+
+    #[test]
+    fn drops_liquidity_beyond_the_last_level() {
+        let asks = vec![segment(100.05, 1.0, 0), segment(100.25, 1.0, 0), segment(101.0, 1.0, 0)];
+        let levels = build_levels(asks, 100.0, options(2), false);
+        // Empty levels are left out rather than listed with nothing in them
+        assert_eq!(prices(&levels), vec![100.05]);
+        assert_eq!(levels[0].cumulative_base, 1.0);
+    }
+}
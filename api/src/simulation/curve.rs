@@ -0,0 +1,187 @@
+use num_bigint::BigUint;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+use serde::Serialize;
+
+use crate::errors::ApiError;
+
+use super::executor::CancelToken;
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+use super::swap::to_units;
+
+/// Smallest curve input as a fraction of the pool's sell limit
+const MIN_FRACTION_OF_LIMIT: f64 = 1e-6;
+
+#[derive(Debug, Serialize)]
+pub struct CurvePoint {
+    pub amount_in: String,
+    pub amount_out: String,
+    /// Amounts in whole tokens, for plotting
+    pub amount_in_units: f64,
+    pub amount_out_units: f64,
+    pub effective_price: f64,
+    pub price_impact_pct: Option<f64>,
+    pub gas: String,
+}
+
+/// Output, price and impact of one pool over log-spaced input sizes
+#[derive(Debug, Serialize)]
+pub struct PriceCurve {
+    pub pool: String,
+    pub block_number: u64,
+    pub sell_token: String,
+    pub buy_token: String,
+    pub spot_price: Option<f64>,
+    /// Most the pool accepts of the sell token, and pays out of the buy token
+    pub max_sell: String,
+    pub max_buy: String,
+    pub points: Vec<CurvePoint>,
+}
+
+/// Parse a direction like "0to1" into the indices of the sell and buy token
+pub fn parse_direction(direction: &str, token_count: usize) -> Result<(usize, usize), ApiError> {
+    let invalid = || {
+        ApiError::InvalidInput(format!(
+            "Invalid direction {}, expected e.g. 0to1 for a pool with {} tokens",
+            direction, token_count
+        ))
+    };
+    let (sell, buy) = direction.split_once("to").ok_or_else(invalid)?;
+    let sell: usize = sell.trim().parse().map_err(|_| invalid())?;
+    let buy: usize = buy.trim().parse().map_err(|_| invalid())?;
+    if sell == buy || sell >= token_count || buy >= token_count {
+        return Err(invalid());
+    }
+    Ok((sell, buy))
+}
+
+/// `points` input sizes spaced logarithmically from a millionth of `limit` up to `limit`
+pub fn log_amounts(limit: &BigUint, points: usize) -> Vec<BigUint> {
+    let Some(max) = limit.to_f64().filter(|max| *max >= 1.0) else {
+        return Vec::new();
+    };
+    let min = (max * MIN_FRACTION_OF_LIMIT).max(1.0);
+    let step = if points > 1 {
+        (max / min).ln() / (points - 1) as f64
+    } else {
+        0.0
+    };
+    let mut amounts: Vec<BigUint> = (0..points)
+        .filter_map(|i| {
+            // Rounding would leave the last point just short of the limit
+            if points > 1 && i == points - 1 {
+                return Some(limit.clone());
+            }
+            BigUint::from_f64((min * (step * i as f64).exp()).floor())
+        })
+        .map(|amount| amount.min(limit.clone()))
+        .filter(|amount| !amount.is_zero())
+        .collect();
+    amounts.dedup();
+    amounts
+}
+
+/// Swap log-spaced amounts through a pool, up to its sell limit. Meant to run
+/// on a blocking worker.
+pub fn price_curve(
+    snapshot: &BlockSnapshot,
+    pool: &str,
+    direction: &str,
+    points: usize,
+    cancel: &CancelToken,
+) -> Result<PriceCurve, ApiError> {
+    let (Some(component), Some(state)) = snapshot.get_pool_state(pool) else {
+        return Err(ApiError::NotFound(format!("Pool not found: {}", pool)));
+    };
+    let (sell_index, buy_index) = parse_direction(direction, component.tokens.len())?;
+    let sell = &component.tokens[sell_index];
+    let buy = &component.tokens[buy_index];
+
+    let (max_sell, max_buy) = isolate(|| state.get_limits(sell.address.clone(), buy.address.clone()))
+        .map_err(|e| ApiError::SimulationError(format!("Pool {}: failed to get limits: {}", pool, e)))?;
+    let spot_price = isolate(|| state.spot_price(sell, buy)).ok();
+
+    let mut curve = Vec::with_capacity(points);
+    for amount_in in log_amounts(&max_sell, points) {
+        if cancel.is_cancelled() {
+            return Err(ApiError::Timeout(format!("Curve for pool {} abandoned", pool)));
+        }
+        // Sizes close to the limit may fail, the curve just ends there
+        let Ok(result) = isolate(|| state.get_amount_out(amount_in.clone(), sell, buy)) else {
+            break;
+        };
+        let amount_in_units = to_units(&amount_in, sell.decimals as u32);
+        let amount_out_units = to_units(&result.amount, buy.decimals as u32);
+        let effective_price = amount_out_units / amount_in_units;
+        curve.push(CurvePoint {
+            amount_in: amount_in.to_string(),
+            amount_out: result.amount.to_string(),
+            amount_in_units,
+            amount_out_units,
+            effective_price,
+            price_impact_pct: spot_price
+                .filter(|spot| *spot > 0.0)
+                .map(|spot| (spot - effective_price) / spot * 100.0),
+            gas: result.gas.to_string(),
+        });
+    }
+
+    Ok(PriceCurve {
+        pool: pool.to_string(),
+        block_number: snapshot.block_number,
+        sell_token: sell.address.to_string(),
+        buy_token: buy.address.to_string(),
+        spot_price,
+        max_sell: max_sell.to_string(),
+        max_buy: max_buy.to_string(),
+        points: curve,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_amounts_span_a_millionth_of_the_limit_to_the_limit() {
+        let limit = BigUint::from(10u32).pow(18);
+        let amounts = log_amounts(&limit, 7);
+        assert_eq!(amounts.len(), 7);
+        assert_eq!(amounts[0], BigUint::from(10u32).pow(12));
+        assert_eq!(amounts.last(), Some(&limit));
+        assert!(amounts.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn log_amounts_never_exceed_or_repeat_for_small_limits() {
+        let limit = BigUint::from(3u32);
+        let amounts = log_amounts(&limit, 10);
+        assert_eq!(
+            amounts,
+            vec![BigUint::from(1u32), BigUint::from(2u32), BigUint::from(3u32)]
+        );
+    }
+
+    #[test]
+    fn log_amounts_are_empty_below_one_unit() {
+        assert!(log_amounts(&BigUint::zero(), 10).is_empty());
+        assert!(log_amounts(&BigUint::from(100u32), 0).is_empty());
+    }
+
+    #[test]
+    fn parses_directions() {
+        assert_eq!(parse_direction("0to1", 2).unwrap(), (0, 1));
+        assert_eq!(parse_direction("2to0", 3).unwrap(), (2, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_directions() {
+        for direction in ["0to0", "0to2", "1-0", "to1", "xto1", ""] {
+            assert!(
+                matches!(parse_direction(direction, 2), Err(ApiError::InvalidInput(_))),
+                "accepted {:?}",
+                direction
+            );
+        }
+    }
+}
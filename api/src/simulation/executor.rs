@@ -0,0 +1,167 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+
+/// Limits applied to simulation jobs
+#[derive(Debug, Clone)]
+pub struct ExecutorConfig {
+    /// Maximum number of request and background jobs running at once
+    pub max_concurrency: usize,
+    /// Workers reserved for block ingestion, on top of `max_concurrency`, so
+    /// requests and background jobs can never hold up new blocks
+    pub ingest_concurrency: usize,
+    /// How long a request may wait for a free worker before being refused
+    pub queue_timeout: Duration,
+    /// How long a request job may run before it is abandoned
+    pub job_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    /// No worker became free within the queue timeout
+    Saturated,
+    /// The job did not finish within the job timeout
+    TimedOut,
+    /// The job panicked
+    Failed(String),
+}
+
+impl fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutorError::Saturated => write!(f, "all simulation workers are busy"),
+            ExecutorError::TimedOut => write!(f, "simulation timed out"),
+            ExecutorError::Failed(e) => write!(f, "simulation failed: {}", e),
+        }
+    }
+}
+
+/// Cooperative cancellation flag handed to jobs. Long-running jobs should
+/// check it between units of work and bail out once it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Cancels the job if the awaiting request goes away
+struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Runs CPU-heavy simulation work (VM spot prices, `get_amount_out`) on
+/// tokio's blocking threads, so it never stalls the async workers that drive
+/// websocket fan-out
+#[derive(Debug, Clone)]
+pub struct SimulationExecutor {
+    config: ExecutorConfig,
+    permits: Arc<Semaphore>,
+    ingest_permits: Arc<Semaphore>,
+}
+
+impl SimulationExecutor {
+    pub fn new(config: ExecutorConfig) -> Self {
+        let permits = Arc::new(Semaphore::new(config.max_concurrency));
+        let ingest_permits = Arc::new(Semaphore::new(config.ingest_concurrency.max(1)));
+        SimulationExecutor {
+            config,
+            permits,
+            ingest_permits,
+        }
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.config.max_concurrency
+    }
+
+    pub fn ingest_concurrency(&self) -> usize {
+        self.config.ingest_concurrency.max(1)
+    }
+
+    /// Run a request-scoped job. Fails fast when saturated, and abandons the
+    /// job (signalling its cancel token) when it exceeds the job timeout or
+    /// the caller stops waiting.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, ExecutorError>
+    where
+        F: FnOnce(&CancelToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = tokio::time::timeout(
+            self.config.queue_timeout,
+            self.permits.clone().acquire_owned(),
+        )
+        .await
+        .map_err(|_| ExecutorError::Saturated)?
+        .expect("executor semaphore is never closed");
+
+        let cancel = CancelToken::default();
+        let _guard = CancelOnDrop(cancel.clone());
+        let handle = tokio::task::spawn_blocking(move || {
+            // Keep the permit until the job really finishes, even if abandoned
+            let _permit = permit;
+            job(&cancel)
+        });
+
+        match tokio::time::timeout(self.config.job_timeout, handle).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(ExecutorError::Failed(e.to_string())),
+            Err(_) => Err(ExecutorError::TimedOut),
+        }
+    }
+
+    /// Run a background job, such as an analysis refreshed every few blocks.
+    /// Waits for a free worker and lets the job run to completion.
+    pub async fn run_to_completion<T, F>(&self, job: F) -> Result<T, ExecutorError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run_with(&self.permits, job).await
+    }
+
+    /// Run part of applying a block, such as computing its spot prices, on
+    /// the workers reserved for ingestion
+    pub async fn run_ingest<T, F>(&self, job: F) -> Result<T, ExecutorError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run_with(&self.ingest_permits, job).await
+    }
+
+    async fn run_with<T, F>(permits: &Arc<Semaphore>, job: F) -> Result<T, ExecutorError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("executor semaphore is never closed");
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await
+        .map_err(|e| ExecutorError::Failed(e.to_string()))
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use tycho_simulation::{
+    evm::protocol::{
+        ekubo::state::EkuboState, pancakeswap_v2::state::PancakeswapV2State,
+        uniswap_v2::state::UniswapV2State, uniswap_v3::state::UniswapV3State,
+        uniswap_v4::state::UniswapV4State,
+    },
+    protocol::models::ProtocolComponent,
+    tycho_core::{simulation::protocol_sim::ProtocolSim, Bytes},
+};
+
+/// Flag in a V4 pool key's fee marking a fee set dynamically by the hook
+const V4_DYNAMIC_FEE_FLAG: u64 = 0x80_0000;
+
+/// Big-endian integer stored in a static attribute
+fn attribute_value(bytes: &Bytes) -> Option<u128> {
+    let bytes = bytes.as_ref();
+    if bytes.len() > 16 {
+        return None;
+    }
+    Some(bytes.iter().fold(0u128, |value, byte| (value << 8) | *byte as u128))
+}
+
+/// Fee in basis points from the component's static attributes, which each
+/// protocol encodes in its own scale
+fn attribute_fee_bps(protocol: &str, attributes: &HashMap<String, Bytes>) -> Option<f64> {
+    let raw = match protocol {
+        "uniswap_v4" => attributes.get("key_lp_fee").or_else(|| attributes.get("fee")),
+        _ => attributes.get("fee"),
+    };
+    let Some(raw) = raw.and_then(attribute_value) else {
+        // Constant product forks that don't carry a fee attribute
+        return match protocol {
+            "uniswap_v2" | "sushiswap_v2" => Some(30.0),
+            "pancakeswap_v2" => Some(25.0),
+            _ => None,
+        };
+    };
+    let raw = raw as f64;
+    match protocol {
+        "uniswap_v2" | "sushiswap_v2" | "pancakeswap_v2" => Some(raw),
+        "vm:balancer_v2" => Some(raw / 1e18 * 10_000.0),
+        // 0.64 fixed point fraction
+        "ekubo_v2" => Some(raw / 2f64.powi(64) * 10_000.0),
+        // A dynamic fee lives in the hook, the key only carries the flag
+        "uniswap_v4" if raw as u64 & V4_DYNAMIC_FEE_FLAG != 0 => None,
+        // Hundredths of a basis point, e.g. 3000 for 0.3%
+        _ => Some(raw / 100.0),
+    }
+}
+
+/// Whether `fee` is a usable fraction of the amount in
+fn valid_fraction(fee: &f64) -> bool {
+    fee.is_finite() && (0.0..1.0).contains(fee)
+}
+
+/// Fraction charged by a V4 LP fee, in hundredths of a basis point. A fee
+/// carrying the dynamic flag is set by the hook and isn't known here.
+fn v4_lp_fee(lp_fee: u32) -> Option<f64> {
+    if lp_fee as u64 & V4_DYNAMIC_FEE_FLAG != 0 {
+        return None;
+    }
+    Some(lp_fee as f64 / 1e6).filter(valid_fraction)
+}
+
+/// Fee charged on the amount in as a fraction, for the state types known to
+/// implement `fee`. Others, such as VM pools, panic when asked.
+pub fn state_fee(state: &dyn ProtocolSim) -> Option<f64> {
+    let any = state.as_any();
+    if let Some(v4) = any.downcast_ref::<UniswapV4State>() {
+        return v4_lp_fee(v4.fees.lp_fee);
+    }
+    let known = any.is::<UniswapV2State>()
+        || any.is::<PancakeswapV2State>()
+        || any.is::<UniswapV3State>()
+        || any.is::<EkuboState>();
+    if !known {
+        return None;
+    }
+    Some(state.fee()).filter(valid_fraction)
+}
+
+/// Fee a pool currently charges, in basis points. States without a known fee
+/// fall back to the component's static attributes; dynamic-fee V4 pools have
+/// neither and report none.
+pub fn fee_bps(state: &dyn ProtocolSim, component: &ProtocolComponent) -> Option<f64> {
+    state_fee(state)
+        .map(|fee| fee * 10_000.0)
+        .or_else(|| attribute_fee_bps(&component.protocol_system, &component.static_attributes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(entries: &[(&str, &[u8])]) -> HashMap<String, Bytes> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), Bytes::from(value.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_big_endian_attributes() {
+        assert_eq!(attribute_value(&Bytes::from(vec![0x0b, 0xb8])), Some(3000));
+        assert_eq!(attribute_value(&Bytes::from(Vec::new())), Some(0));
+        assert_eq!(attribute_value(&Bytes::from(vec![0xff; 17])), None);
+    }
+
+    #[test]
+    fn scales_fees_per_protocol() {
+        // 3000 hundredths of a basis point
+        let v3 = attributes(&[("fee", &[0x0b, 0xb8])]);
+        assert_eq!(attribute_fee_bps("uniswap_v3", &v3), Some(30.0));
+        // Basis points
+        let v2 = attributes(&[("fee", &[30])]);
+        assert_eq!(attribute_fee_bps("uniswap_v2", &v2), Some(30.0));
+        // 0.3% as an 18 decimal fraction
+        let balancer = attributes(&[("fee", &3_000_000_000_000_000u64.to_be_bytes())]);
+        assert_eq!(attribute_fee_bps("vm:balancer_v2", &balancer), Some(30.0));
+        // 2^-7 as a 0.64 fixed point fraction
+        let ekubo = attributes(&[("fee", &(1u64 << 57).to_be_bytes())]);
+        assert_eq!(attribute_fee_bps("ekubo_v2", &ekubo), Some(78.125));
+    }
+
+    #[test]
+    fn prefers_the_v4_key_fee_and_skips_dynamic_fees() {
+        let v4 = attributes(&[("key_lp_fee", &[0x01, 0xf4]), ("fee", &[0x0b, 0xb8])]);
+        assert_eq!(attribute_fee_bps("uniswap_v4", &v4), Some(5.0));
+        let dynamic = attributes(&[("key_lp_fee", &[0x80, 0x00, 0x00])]);
+        assert_eq!(attribute_fee_bps("uniswap_v4", &dynamic), None);
+    }
+
+    #[test]
+    fn defaults_constant_product_forks_without_a_fee_attribute() {
+        let empty = HashMap::new();
+        assert_eq!(attribute_fee_bps("uniswap_v2", &empty), Some(30.0));
+        assert_eq!(attribute_fee_bps("sushiswap_v2", &empty), Some(30.0));
+        assert_eq!(attribute_fee_bps("pancakeswap_v2", &empty), Some(25.0));
+        assert_eq!(attribute_fee_bps("uniswap_v3", &empty), None);
+    }
+
+    #[test]
+    fn reads_static_v4_lp_fees() {
+        assert_eq!(v4_lp_fee(3000), Some(0.003));
+        assert_eq!(v4_lp_fee(0), Some(0.0));
+        // 100% or more isn't a fee a swap can pay
+        assert_eq!(v4_lp_fee(1_000_000), None);
+    }
+
+    #[test]
+    fn skips_flagged_v4_lp_fees() {
+        assert_eq!(v4_lp_fee(0x80_0000), None);
+        assert_eq!(v4_lp_fee(0x80_0000 | 3000), None);
+    }
+}
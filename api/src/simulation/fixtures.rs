@@ -0,0 +1,60 @@
+//! Snapshots of priced pools for unit tests, without any pool state
+
+use std::{collections::HashMap, sync::Arc};
+use tycho_simulation::{
+    protocol::models::ProtocolComponent,
+    tycho_core::{
+        models::{token::Token, Chain},
+        Bytes,
+    },
+};
+
+use super::spot_prices::PairPrice;
+use super::state::BlockSnapshot;
+
+/// Token whose address is the single byte `id`, e.g. "0x01"
+pub fn token(id: u8, decimals: u32) -> Token {
+    Token::new(
+        &Bytes::from(vec![id]),
+        &format!("T{}", id),
+        decimals,
+        0,
+        &[Some(0)],
+        Chain::Ethereum,
+        100,
+    )
+}
+
+pub fn address(token: &Token) -> String {
+    token.address.to_string()
+}
+
+pub fn component(id: &str, protocol_system: &str, tokens: &[&Token]) -> ProtocolComponent {
+    ProtocolComponent::new(
+        Bytes::from(id.as_bytes().to_vec()),
+        protocol_system.to_string(),
+        format!("{}_pool", protocol_system),
+        Chain::Ethereum,
+        tokens.iter().map(|token| (*token).clone()).collect(),
+        Vec::new(),
+        HashMap::new(),
+        Bytes::default(),
+        Default::default(),
+    )
+}
+
+/// Add a two-token pool quoting `price` of `quote` per `base`
+pub fn add_pool(snapshot: &mut BlockSnapshot, id: &str, base: &Token, quote: &Token, price: f64) {
+    snapshot
+        .components
+        .insert(id.to_string(), Arc::new(component(id, "uniswap_v2", &[base, quote])));
+    snapshot.spot_prices.insert(id.to_string(), price);
+    snapshot.pair_prices.insert(
+        id.to_string(),
+        vec![PairPrice {
+            base: address(base),
+            quote: address(quote),
+            price,
+        }],
+    );
+}
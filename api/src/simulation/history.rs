@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, warn};
+
+use super::pricing::tvl_estimate;
+use super::state::{ClientUpdate, SimulationState};
+
+/// Write the history to disk every this many recorded updates
+const PERSIST_EVERY_UPDATES: u64 = 50;
+
+/// A pool's price and TVL as of one block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub block_number: u64,
+    /// Unix timestamp the block was processed at
+    pub timestamp: u64,
+    pub spot_price: f64,
+    /// Estimated from the pool's limits, in the numeraire. Only set on the
+    /// blocks TVL was sampled at.
+    pub tvl_estimate: Option<f64>,
+}
+
+type Series = HashMap<String, VecDeque<HistoryPoint>>;
+
+/// Recent spot price and TVL of every pool. Updates are read from a
+/// coalescing outbox, so blocks that arrive while the recorder is behind are
+/// merged and a pool gets one point at the newest of them, not one per block.
+#[derive(Debug, Clone)]
+pub struct PriceHistory {
+    max_points: usize,
+    series: Arc<RwLock<Series>>,
+}
+
+impl PriceHistory {
+    pub fn new(max_points: usize) -> Self {
+        PriceHistory {
+            max_points,
+            series: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Points of `pool` between blocks `from` and `to`, both inclusive. `None`
+    /// if nothing was recorded for the pool.
+    pub fn range(&self, pool: &str, from: Option<u64>, to: Option<u64>) -> Option<Vec<HistoryPoint>> {
+        let series = self.series.read().unwrap();
+        let points = series.get(pool)?;
+        Some(
+            points
+                .iter()
+                .filter(|point| from.is_none_or(|from| point.block_number >= from))
+                .filter(|point| to.is_none_or(|to| point.block_number <= to))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Fold an update into the history. A revert drops every point past the
+    /// block it rolled back to before recording the restored prices.
+    fn record(&self, update: &ClientUpdate, tvl: HashMap<String, f64>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut series = self.series.write().unwrap();
+        if let Some(revert) = update.revert {
+            for points in series.values_mut() {
+                while points.back().is_some_and(|point| point.block_number > revert.reverted_to) {
+                    points.pop_back();
+                }
+            }
+            series.retain(|_, points| !points.is_empty());
+        }
+        for id in update.removed_pairs.keys() {
+            series.remove(id);
+        }
+        for (id, spot_price) in &update.spot_prices {
+            let points = series.entry(id.clone()).or_default();
+            if points.len() == self.max_points {
+                points.pop_front();
+            }
+            points.push_back(HistoryPoint {
+                block_number: update.block_number,
+                timestamp,
+                spot_price: *spot_price,
+                tvl_estimate: tvl.get(id).copied(),
+            });
+        }
+    }
+
+    /// Load a previously persisted history, keeping the newest points per pool
+    pub async fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut loaded: Series = serde_json::from_slice(&bytes)?;
+        for points in loaded.values_mut() {
+            let excess = points.len().saturating_sub(self.max_points);
+            points.drain(..excess);
+        }
+        info!("Loaded price history of {} pools from {}", loaded.len(), path.display());
+        *self.series.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    async fn persist(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(&*self.series.read().unwrap())?;
+        // Write aside and rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Record every block update for as long as the process lives. TVL is
+    /// sampled at most once every `tvl_every_blocks` blocks, never if 0.
+    pub fn spawn(
+        &self,
+        state: SimulationState,
+        persist_path: Option<PathBuf>,
+        tvl_every_blocks: u64,
+    ) {
+        let history = self.clone();
+        tokio::spawn(async move {
+            let subscription = state.subscribe_to_updates();
+            let mut recorded = 0u64;
+            let mut last_tvl_block: Option<u64> = None;
+            loop {
+                let update = subscription.outbox().recv().await;
+                let merged = subscription.outbox().take_coalesced();
+                if merged > 0 {
+                    debug!("Price history merged {} updates into block {}", merged, update.block_number);
+                }
+                let sample_tvl = tvl_every_blocks > 0
+                    && last_tvl_block.is_none_or(|last| {
+                        update.block_number < last || update.block_number - last >= tvl_every_blocks
+                    });
+                let tvl = if sample_tvl {
+                    last_tvl_block = Some(update.block_number);
+                    // Limits can be slow for VM pools, so estimate TVL on the workers
+                    let snapshot = state.snapshot();
+                    let ids: Vec<String> = update.spot_prices.keys().cloned().collect();
+                    state
+                        .executor()
+                        .run_to_completion(move || {
+                            ids.into_iter()
+                                .filter_map(|id| {
+                                    let (Some(component), Some(pool)) = snapshot.get_pool_state(&id) else {
+                                        return None;
+                                    };
+                                    let tvl = tvl_estimate(&snapshot, pool, &component.tokens)?;
+                                    Some((id, tvl))
+                                })
+                                .collect::<HashMap<_, _>>()
+                        })
+                        .await
+                        .unwrap_or_else(|e| {
+                            warn!("Skipping TVL of block {}: {}", update.block_number, e);
+                            HashMap::new()
+                        })
+                } else {
+                    HashMap::new()
+                };
+                history.record(&update, tvl);
+
+                recorded += 1;
+                if let Some(path) = &persist_path {
+                    if recorded % PERSIST_EVERY_UPDATES == 0 {
+                        if let Err(e) = history.persist(path).await {
+                            warn!("Failed to persist price history to {}: {}", path.display(), e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::state::RevertInfo;
+
+    fn priced(block_number: u64, prices: &[(&str, f64)]) -> ClientUpdate {
+        ClientUpdate {
+            block_number,
+            spot_prices: prices.iter().map(|(id, price)| (id.to_string(), *price)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn blocks(history: &PriceHistory, pool: &str) -> Vec<u64> {
+        history
+            .range(pool, None, None)
+            .unwrap_or_default()
+            .iter()
+            .map(|point| point.block_number)
+            .collect()
+    }
+
+    #[test]
+    fn record_keeps_the_newest_points() {
+        let history = PriceHistory::new(3);
+        for block in 1..=5 {
+            history.record(&priced(block, &[("a", block as f64)]), HashMap::new());
+        }
+        assert_eq!(blocks(&history, "a"), vec![3, 4, 5]);
+        assert_eq!(
+            history.range("a", Some(4), Some(4)).unwrap()[0].spot_price,
+            4.0
+        );
+    }
+
+    #[test]
+    fn record_trims_reverted_blocks() {
+        let history = PriceHistory::new(10);
+        history.record(&priced(1, &[("a", 1.0)]), HashMap::new());
+        history.record(&priced(2, &[("a", 2.0)]), HashMap::new());
+        history.record(&priced(3, &[("a", 3.0), ("b", 3.0)]), HashMap::new());
+
+        let mut revert = priced(1, &[("a", 1.5)]);
+        revert.revert = Some(RevertInfo {
+            reverted_from: 3,
+            reverted_to: 1,
+        });
+        history.record(&revert, HashMap::new());
+
+        assert_eq!(blocks(&history, "a"), vec![1, 1]);
+        assert_eq!(history.range("a", None, None).unwrap()[1].spot_price, 1.5);
+        // A pool only seen in reverted blocks is dropped
+        assert!(history.range("b", None, None).is_none());
+    }
+}
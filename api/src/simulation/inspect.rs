@@ -0,0 +1,221 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tycho_simulation::{
+    evm::{
+        engine_db::tycho_db::PreCachedDB,
+        protocol::{
+            ekubo::state::EkuboState, pancakeswap_v2::state::PancakeswapV2State,
+            uniswap_v2::state::UniswapV2State, uniswap_v3::state::UniswapV3State,
+            uniswap_v4::state::UniswapV4State, vm::state::EVMPoolState,
+        },
+    },
+    protocol::models::ProtocolComponent,
+    tycho_core::simulation::protocol_sim::ProtocolSim,
+};
+
+use crate::errors::ApiError;
+
+use super::quarantine::QuarantineEntry;
+use super::spot_prices::{OrientedPrice, PairPrice};
+use super::state::BlockSnapshot;
+
+/// Key parameters of a pool state, by the state type it is simulated with.
+/// Large integers are decimal strings.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateView {
+    UniswapV2 {
+        reserve0: String,
+        reserve1: String,
+    },
+    UniswapV3 {
+        liquidity: String,
+        sqrt_price_x96: String,
+        tick: i32,
+        /// Fee tier in hundredths of a basis point, e.g. 3000 for 0.3%
+        fee_tier: i32,
+        initialized_ticks: usize,
+    },
+    UniswapV4 {
+        liquidity: String,
+        sqrt_price_x96: String,
+        tick: i32,
+        tick_spacing: i32,
+        /// Fees in hundredths of a basis point
+        lp_fee: u32,
+        protocol_fee_zero_for_one: u32,
+        protocol_fee_one_for_zero: u32,
+        initialized_ticks: usize,
+        /// Hook contract, if the pool has one
+        hooks: Option<String>,
+    },
+    Ekubo {
+        /// Pool extension the state models, e.g. base or oracle
+        pool_type: String,
+        liquidity: String,
+        /// Square root of the price as a 64.128 fixed point number
+        sqrt_ratio: String,
+        /// Initialized tick at or below the current price. Only base pools
+        /// have ticks; full range and oracle pools span every price.
+        tick: Option<i32>,
+        initialized_ticks: usize,
+    },
+    Vm {
+        /// Token balances the VM simulation runs against, in base units
+        balances: BTreeMap<String, String>,
+    },
+    /// A state type without a typed view
+    Unknown,
+}
+
+impl StateView {
+    pub fn of(state: &dyn ProtocolSim, component: &ProtocolComponent) -> Self {
+        let any = state.as_any();
+        if let Some(v2) = any.downcast_ref::<UniswapV2State>() {
+            return StateView::UniswapV2 {
+                reserve0: v2.reserve0.to_string(),
+                reserve1: v2.reserve1.to_string(),
+            };
+        }
+        if let Some(v2) = any.downcast_ref::<PancakeswapV2State>() {
+            return StateView::UniswapV2 {
+                reserve0: v2.reserve0.to_string(),
+                reserve1: v2.reserve1.to_string(),
+            };
+        }
+        if let Some(v3) = any.downcast_ref::<UniswapV3State>() {
+            return StateView::UniswapV3 {
+                liquidity: v3.liquidity.to_string(),
+                sqrt_price_x96: v3.sqrt_price.to_string(),
+                tick: v3.tick,
+                fee_tier: v3.fee as i32,
+                initialized_ticks: v3.ticks.ticks.len(),
+            };
+        }
+        if let Some(v4) = any.downcast_ref::<UniswapV4State>() {
+            return StateView::UniswapV4 {
+                liquidity: v4.liquidity.to_string(),
+                sqrt_price_x96: v4.sqrt_price.to_string(),
+                tick: v4.tick,
+                tick_spacing: v4.tick_spacing,
+                lp_fee: v4.fees.lp_fee,
+                protocol_fee_zero_for_one: v4.fees.zero_for_one,
+                protocol_fee_one_for_zero: v4.fees.one_for_zero,
+                initialized_ticks: v4.ticks.ticks.len(),
+                // The hook address is only known from the component
+                hooks: component
+                    .static_attributes
+                    .get("hooks")
+                    .map(|hooks| hooks.to_string()),
+            };
+        }
+        if let Some(ekubo) = any.downcast_ref::<EkuboState>() {
+            return match ekubo {
+                EkuboState::Base(pool) => {
+                    let state = pool.imp.get_state();
+                    let ticks = pool.imp.get_sorted_ticks();
+                    StateView::Ekubo {
+                        pool_type: "base".to_string(),
+                        liquidity: state.liquidity.to_string(),
+                        sqrt_ratio: state.sqrt_ratio.to_string(),
+                        tick: state
+                            .active_tick_index
+                            .and_then(|index| ticks.get(index))
+                            .map(|tick| tick.index),
+                        initialized_ticks: ticks.len(),
+                    }
+                }
+                EkuboState::FullRange(pool) => {
+                    let state = pool.imp.get_state();
+                    StateView::Ekubo {
+                        pool_type: "full_range".to_string(),
+                        liquidity: state.liquidity.to_string(),
+                        sqrt_ratio: state.sqrt_ratio.to_string(),
+                        tick: None,
+                        initialized_ticks: 0,
+                    }
+                }
+                EkuboState::Oracle(pool) => {
+                    let state = pool.imp.get_state().full_range_pool_state;
+                    StateView::Ekubo {
+                        pool_type: "oracle".to_string(),
+                        liquidity: state.liquidity.to_string(),
+                        sqrt_ratio: state.sqrt_ratio.to_string(),
+                        tick: None,
+                        initialized_ticks: 0,
+                    }
+                }
+            };
+        }
+        if let Some(vm) = any.downcast_ref::<EVMPoolState<PreCachedDB>>() {
+            return StateView::Vm {
+                balances: vm
+                    .get_balances()
+                    .iter()
+                    .map(|(token, balance)| (token.to_string(), balance.to_string()))
+                    .collect(),
+            };
+        }
+        StateView::Unknown
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolToken {
+    pub address: String,
+    pub symbol: String,
+    pub decimals: u32,
+}
+
+/// Everything known about one pool as of the latest block
+#[derive(Debug, Serialize)]
+pub struct PoolDetail {
+    pub pool: String,
+    pub protocol_system: String,
+    pub block_number: u64,
+    pub tokens: Vec<PoolToken>,
+    pub static_attributes: BTreeMap<String, String>,
+    /// Fee the pool currently charges, in basis points
+    pub fee_bps: Option<f64>,
+    pub spot_prices: Vec<PairPrice>,
+    /// Spot price quoted in the first requested quote token the pool trades
+    pub price: Option<OrientedPrice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantine: Option<QuarantineEntry>,
+    pub state: StateView,
+}
+
+pub fn pool_detail(
+    snapshot: &BlockSnapshot,
+    pool: &str,
+    quote_tokens: &[String],
+) -> Result<PoolDetail, ApiError> {
+    let (Some(component), Some(state)) = snapshot.get_pool_state(pool) else {
+        return Err(ApiError::NotFound(format!("Pool not found: {}", pool)));
+    };
+    let spot_prices = snapshot.pair_prices.get(pool).cloned().unwrap_or_default();
+    Ok(PoolDetail {
+        pool: pool.to_string(),
+        protocol_system: component.protocol_system.clone(),
+        block_number: snapshot.block_number,
+        tokens: component
+            .tokens
+            .iter()
+            .map(|token| PoolToken {
+                address: token.address.to_string(),
+                symbol: token.symbol.clone(),
+                decimals: token.decimals as u32,
+            })
+            .collect(),
+        static_attributes: component
+            .static_attributes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_string()))
+            .collect(),
+        fee_bps: snapshot.fees.get(pool).copied(),
+        price: OrientedPrice::orient(&spot_prices, quote_tokens),
+        spot_prices,
+        quarantine: snapshot.quarantined.get(pool).cloned(),
+        state: StateView::of(state, component),
+    })
+}
@@ -0,0 +1,240 @@
+use serde::Serialize;
+use tycho_simulation::{
+    evm::protocol::{
+        ekubo::state::EkuboState, uniswap_v3::state::UniswapV3State,
+        uniswap_v4::state::UniswapV4State,
+    },
+    tycho_core::simulation::protocol_sim::ProtocolSim,
+};
+
+use crate::errors::ApiError;
+
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+
+/// Price ratio between neighbouring ticks
+const UNISWAP_TICK_BASE: f64 = 1.0001;
+const EKUBO_TICK_BASE: f64 = 1.000001;
+
+/// Liquidity active between two initialized ticks
+#[derive(Debug, Serialize)]
+pub struct LiquidityRange {
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    /// Range bounds in token1 per token0, adjusted for decimals
+    pub price_lower: f64,
+    pub price_upper: f64,
+    pub liquidity: String,
+    /// Tokens locked in the range at the current price, in whole tokens
+    pub amount0: f64,
+    pub amount1: f64,
+    /// Whether the current price lies in the range
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LiquidityDistribution {
+    pub pool: String,
+    pub protocol_system: String,
+    pub block_number: u64,
+    pub token0: String,
+    pub token1: String,
+    pub current_tick: i32,
+    /// Spot price in token1 per token0
+    pub current_price: Option<f64>,
+    pub active_liquidity: String,
+    /// Ranges ordered by tick, around the current price
+    pub ranges: Vec<LiquidityRange>,
+}
+
+/// What a concentrated liquidity state tells about its ticks
+struct TickData {
+    tick_base: f64,
+    /// None when it has to be derived from the spot price
+    current_tick: Option<i32>,
+    liquidity: u128,
+    /// Initialized ticks and the liquidity added when crossing them upwards
+    ticks: Vec<(i32, i128)>,
+}
+
+fn tick_data(state: &dyn ProtocolSim) -> Option<TickData> {
+    let any = state.as_any();
+    if let Some(v3) = any.downcast_ref::<UniswapV3State>() {
+        return Some(TickData {
+            tick_base: UNISWAP_TICK_BASE,
+            current_tick: Some(v3.tick),
+            liquidity: v3.liquidity,
+            ticks: v3.ticks.ticks.iter().map(|t| (t.index, t.net_liquidity)).collect(),
+        });
+    }
+    if let Some(v4) = any.downcast_ref::<UniswapV4State>() {
+        return Some(TickData {
+            tick_base: UNISWAP_TICK_BASE,
+            current_tick: Some(v4.tick),
+            liquidity: v4.liquidity,
+            ticks: v4.ticks.ticks.iter().map(|t| (t.index, t.net_liquidity)).collect(),
+        });
+    }
+    if let Some(EkuboState::Base(pool)) = any.downcast_ref::<EkuboState>() {
+        return Some(TickData {
+            tick_base: EKUBO_TICK_BASE,
+            current_tick: None,
+            liquidity: pool.imp.get_state().liquidity,
+            ticks: pool
+                .imp
+                .get_sorted_ticks()
+                .iter()
+                .map(|t| (t.index, t.liquidity_delta))
+                .collect(),
+        });
+    }
+    None
+}
+
+/// Tokens locked in a range with liquidity `liquidity`, in base units. Tick
+/// prices are raw, i.e. token1 base units per token0 base unit.
+fn range_amounts(tick_base: f64, lower: i32, upper: i32, current: f64, liquidity: f64) -> (f64, f64) {
+    let sqrt_lower = tick_base.powf(lower as f64 / 2.0);
+    let sqrt_upper = tick_base.powf(upper as f64 / 2.0);
+    let sqrt_current = current.sqrt().clamp(sqrt_lower, sqrt_upper);
+    (
+        liquidity * (1.0 / sqrt_current - 1.0 / sqrt_upper),
+        liquidity * (sqrt_current - sqrt_lower),
+    )
+}
+
+/// Active liquidity per tick range of a concentrated liquidity pool, with up
+/// to `ranges` initialized ranges on each side of the current price
+pub fn liquidity_distribution(
+    snapshot: &BlockSnapshot,
+    pool: &str,
+    ranges: usize,
+) -> Result<LiquidityDistribution, ApiError> {
+    let (Some(component), Some(state)) = snapshot.get_pool_state(pool) else {
+        return Err(ApiError::NotFound(format!("Pool not found: {}", pool)));
+    };
+    let data = tick_data(state).ok_or_else(|| {
+        ApiError::InvalidInput(format!(
+            "Pool {} ({}) has no tick liquidity distribution",
+            pool, component.protocol_system
+        ))
+    })?;
+    let [token0, token1] = &component.tokens[..] else {
+        return Err(ApiError::InvalidInput(format!("Pool {} is not a two-token pool", pool)));
+    };
+    let decimals_shift = 10f64.powi(token0.decimals as i32 - token1.decimals as i32);
+    let current_price = isolate(|| state.spot_price(token0, token1)).ok();
+    let current_raw = current_price.map(|price| price / decimals_shift);
+    let current_tick = match (data.current_tick, current_raw) {
+        (Some(tick), _) => tick,
+        (None, Some(raw)) if raw > 0.0 => (raw.ln() / data.tick_base.ln()).floor() as i32,
+        (None, _) => {
+            return Err(ApiError::SimulationError(format!("Pool {}: failed to get spot price", pool)))
+        }
+    };
+    let current_raw = current_raw.unwrap_or_else(|| data.tick_base.powi(current_tick));
+
+    let mut ticks = data.ticks;
+    ticks.sort_by_key(|(index, _)| *index);
+    let mut all_ranges = Vec::with_capacity(ticks.len().saturating_sub(1));
+    let mut liquidity: i128 = 0;
+    for window in ticks.windows(2) {
+        let ((lower, net), (upper, _)) = (window[0], window[1]);
+        liquidity = liquidity.saturating_add(net);
+        all_ranges.push((lower, upper, liquidity.max(0) as u128));
+    }
+
+    // Keep the range holding the current tick and its neighbours
+    let current_index = all_ranges
+        .iter()
+        .position(|(lower, upper, _)| *lower <= current_tick && current_tick < *upper)
+        .unwrap_or_else(|| all_ranges.partition_point(|(lower, _, _)| *lower <= current_tick));
+    let start = current_index.saturating_sub(ranges);
+    let end = (current_index + ranges + 1).min(all_ranges.len());
+
+    let ranges = all_ranges[start..end]
+        .iter()
+        .map(|&(lower, upper, liquidity)| {
+            let (amount0, amount1) =
+                range_amounts(data.tick_base, lower, upper, current_raw, liquidity as f64);
+            LiquidityRange {
+                tick_lower: lower,
+                tick_upper: upper,
+                price_lower: data.tick_base.powi(lower) * decimals_shift,
+                price_upper: data.tick_base.powi(upper) * decimals_shift,
+                liquidity: liquidity.to_string(),
+                amount0: amount0 / 10f64.powi(token0.decimals as i32),
+                amount1: amount1 / 10f64.powi(token1.decimals as i32),
+                active: lower <= current_tick && current_tick < upper,
+            }
+        })
+        .collect();
+
+    Ok(LiquidityDistribution {
+        pool: pool.to_string(),
+        protocol_system: component.protocol_system.clone(),
+        block_number: snapshot.block_number,
+        token0: token0.address.to_string(),
+        token1: token1.address.to_string(),
+        current_tick,
+        current_price,
+        active_liquidity: data.liquidity.to_string(),
+        ranges,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_BASE: f64 = 1.0001;
+    const LIQUIDITY: f64 = 1e18;
+
+    fn price(tick: i32) -> f64 {
+        TICK_BASE.powi(tick)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn holds_only_token0_above_the_price() {
+        let (amount0, amount1) = range_amounts(TICK_BASE, 100, 200, price(50), LIQUIDITY);
+        assert_close(amount0, LIQUIDITY * (1.0 / price(100).sqrt() - 1.0 / price(200).sqrt()));
+        assert_eq!(amount1, 0.0);
+    }
+
+    #[test]
+    fn holds_only_token1_below_the_price() {
+        let (amount0, amount1) = range_amounts(TICK_BASE, -200, -100, price(-50), LIQUIDITY);
+        assert_eq!(amount0, 0.0);
+        assert_close(amount1, LIQUIDITY * (price(-100).sqrt() - price(-200).sqrt()));
+
+        // The upper tick itself is already out of token0
+        let (amount0, _) = range_amounts(TICK_BASE, -200, -100, price(-100), LIQUIDITY);
+        assert_eq!(amount0, 0.0);
+    }
+
+    #[test]
+    fn splits_a_range_straddling_the_price() {
+        let (amount0, amount1) = range_amounts(TICK_BASE, -100, 300, price(60), LIQUIDITY);
+        // Each side holds what the part of the range on that side would alone
+        let (above, _) = range_amounts(TICK_BASE, 60, 300, price(60), LIQUIDITY);
+        let (_, below) = range_amounts(TICK_BASE, -100, 60, price(60), LIQUIDITY);
+        assert!(amount0 > 0.0 && amount1 > 0.0);
+        assert_close(amount0, above);
+        assert_close(amount1, below);
+    }
+
+    #[test]
+    fn balances_a_symmetric_range_at_a_price_of_one() {
+        let (amount0, amount1) = range_amounts(TICK_BASE, -500, 500, 1.0, LIQUIDITY);
+        assert_close(amount0, amount1);
+    }
+}
@@ -1,4 +1,25 @@
+pub mod amounts;
+pub mod arbitrage;
+pub mod book;
+pub mod curve;
+pub mod executor;
+pub mod fees;
+#[cfg(test)]
+mod fixtures;
+pub mod history;
+pub mod inspect;
+pub mod liquidity;
+pub mod outbox;
+pub mod overrides;
+pub mod pairs;
+pub mod participation;
+pub mod pricing;
+pub mod quarantine;
+pub mod router;
+pub mod sandbox;
+pub mod spot_prices;
 pub mod state;
+pub mod swap;
 
 use futures::StreamExt;
 use tokio::{sync::mpsc, task::JoinHandle};
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+use super::state::ClientUpdate;
+
+/// Per-client mailbox holding at most one pending update.
+///
+/// Updates pushed while the client is still busy sending the previous one are
+/// merged into the pending update instead of being queued, so a slow client
+/// receives fewer messages but never misses a pool change.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    pending: Mutex<Option<Arc<ClientUpdate>>>,
+    notify: Notify,
+    coalesced: AtomicU64,
+}
+
+impl Outbox {
+    fn push(&self, update: &Arc<ClientUpdate>) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.as_mut() {
+                Some(existing) => {
+                    Arc::make_mut(existing).merge(update);
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+                None => *pending = Some(update.clone()),
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next (possibly merged) update
+    pub async fn recv(&self) -> Arc<ClientUpdate> {
+        loop {
+            let pending = self.pending.lock().unwrap().take();
+            if let Some(update) = pending {
+                return update;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Number of updates merged into others since the last call
+    pub fn take_coalesced(&self) -> u64 {
+        self.coalesced.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct SubscribersInner {
+    next_id: u64,
+    outboxes: HashMap<u64, Arc<Outbox>>,
+}
+
+/// Fan-out of client updates to every subscribed outbox
+#[derive(Debug, Clone, Default)]
+pub struct Subscribers {
+    inner: Arc<Mutex<SubscribersInner>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self) -> Subscription {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let outbox = Arc::new(Outbox::default());
+        inner.outboxes.insert(id, outbox.clone());
+        Subscription {
+            id,
+            outbox,
+            subscribers: self.clone(),
+        }
+    }
+
+    pub fn publish(&self, update: ClientUpdate) {
+        let update = Arc::new(update);
+        let inner = self.inner.lock().unwrap();
+        for outbox in inner.outboxes.values() {
+            outbox.push(&update);
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.inner.lock().unwrap().outboxes.remove(&id);
+    }
+}
+
+/// Handle to a client's outbox, unsubscribes when dropped
+pub struct Subscription {
+    id: u64,
+    outbox: Arc<Outbox>,
+    subscribers: Subscribers,
+}
+
+impl Subscription {
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.unsubscribe(self.id);
+    }
+}
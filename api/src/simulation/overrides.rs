@@ -0,0 +1,327 @@
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use tycho_simulation::{
+    evm::protocol::{
+        u256_num::{biguint_to_u256, u256_to_biguint},
+        uniswap_v2::state::UniswapV2State,
+        uniswap_v3::{enums::FeeAmount, state::UniswapV3State},
+        utils::uniswap::tick_list::TickInfo,
+    },
+    protocol::models::ProtocolComponent,
+    tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim, Bytes},
+};
+
+use crate::errors::ApiError;
+
+use super::fees::fee_bps;
+use super::pricing::numeraire_prices;
+use super::spot_prices::compute_batch;
+use super::state::BlockSnapshot;
+
+/// Precision kept when scaling reserves by a float factor
+const SCALE_PRECISION: u64 = 1_000_000;
+
+/// Pool state to put in place of the real one. Token amounts are raw base
+/// units, and token0/token1 are the pool's tokens sorted by address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PoolStateSpec {
+    UniswapV2 {
+        reserve0: String,
+        reserve1: String,
+    },
+    UniswapV3 {
+        liquidity: String,
+        sqrt_price_x96: String,
+        tick: i32,
+        /// Fee tier in hundredths of a bip, e.g. 3000 for 0.3%
+        fee: i32,
+        #[serde(default)]
+        ticks: Vec<TickSpec>,
+    },
+    /// Multiply the reserves of a Uniswap V2 style pool, e.g. 2.0 for twice the liquidity
+    ScaleReserves { factor: f64 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TickSpec {
+    pub index: i32,
+    pub net_liquidity: String,
+}
+
+/// Replace the state of an existing pool
+#[derive(Debug, Clone, Deserialize)]
+pub struct StateOverride {
+    pub pool: String,
+    #[serde(flatten)]
+    pub state: PoolStateSpec,
+}
+
+/// A pool that doesn't exist on chain, e.g. one about to be deployed
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyntheticPool {
+    /// Address to list the pool under, must be hex
+    pub id: String,
+    pub tokens: Vec<String>,
+    #[serde(flatten)]
+    pub state: PoolStateSpec,
+}
+
+/// Hypothetical changes to the pool graph
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WhatIf {
+    #[serde(default)]
+    pub overrides: Vec<StateOverride>,
+    #[serde(default)]
+    pub synthetic_pools: Vec<SyntheticPool>,
+}
+
+fn parse_uint(field: &str, value: &str) -> Result<BigUint, ApiError> {
+    BigUint::from_str(value.trim())
+        .map_err(|_| ApiError::InvalidInput(format!("{} must be a whole number: {}", field, value)))
+}
+
+fn invalid(pool: &str, message: impl std::fmt::Display) -> ApiError {
+    ApiError::InvalidInput(format!("Pool {}: {}", pool, message))
+}
+
+/// Build the state described by `spec`. `current` is the pool's present state,
+/// needed by specs that adjust rather than replace it.
+fn build_state(
+    pool: &str,
+    spec: &PoolStateSpec,
+    current: Option<&dyn ProtocolSim>,
+) -> Result<Box<dyn ProtocolSim>, ApiError> {
+    match spec {
+        PoolStateSpec::UniswapV2 { reserve0, reserve1 } => Ok(Box::new(UniswapV2State::new(
+            biguint_to_u256(&parse_uint("reserve0", reserve0)?),
+            biguint_to_u256(&parse_uint("reserve1", reserve1)?),
+        ))),
+        PoolStateSpec::UniswapV3 {
+            liquidity,
+            sqrt_price_x96,
+            tick,
+            fee,
+            ticks,
+        } => {
+            let liquidity = parse_uint("liquidity", liquidity)?
+                .to_u128()
+                .ok_or_else(|| invalid(pool, "liquidity does not fit in 128 bits"))?;
+            let sqrt_price = biguint_to_u256(&parse_uint("sqrt_price_x96", sqrt_price_x96)?);
+            let fee = FeeAmount::try_from(*fee)
+                .map_err(|_| invalid(pool, format!("unsupported fee tier {}", fee)))?;
+            let mut tick_list = Vec::with_capacity(ticks.len());
+            for tick in ticks {
+                let net_liquidity = i128::from_str(tick.net_liquidity.trim()).map_err(|_| {
+                    invalid(pool, format!("invalid net_liquidity at tick {}", tick.index))
+                })?;
+                tick_list.push(
+                    TickInfo::new(tick.index, net_liquidity).map_err(|e| invalid(pool, e))?,
+                );
+            }
+            tick_list.sort_by_key(|tick| tick.index);
+            let state = UniswapV3State::new(liquidity, sqrt_price, fee, *tick, tick_list)
+                .map_err(|e| invalid(pool, e))?;
+            Ok(Box::new(state))
+        }
+        PoolStateSpec::ScaleReserves { factor } => {
+            if !factor.is_finite() || *factor <= 0.0 {
+                return Err(invalid(pool, "factor must be positive"));
+            }
+            let state = current
+                .and_then(|state| state.as_any().downcast_ref::<UniswapV2State>())
+                .ok_or_else(|| invalid(pool, "scale_reserves only supports Uniswap V2 style pools"))?;
+            let numerator = BigUint::from((factor * SCALE_PRECISION as f64).round() as u64);
+            let scale = |reserve| u256_to_biguint(reserve) * &numerator / SCALE_PRECISION;
+            Ok(Box::new(UniswapV2State::new(
+                biguint_to_u256(&scale(state.reserve0)),
+                biguint_to_u256(&scale(state.reserve1)),
+            )))
+        }
+    }
+}
+
+/// Component listing a synthetic pool, with the static attributes its
+/// protocol carries taken from the spec rather than from any real pool
+fn synthetic_component(
+    id: &str,
+    address: Bytes,
+    tokens: Vec<Token>,
+    spec: &PoolStateSpec,
+) -> Result<ProtocolComponent, ApiError> {
+    let (protocol, attributes) = match spec {
+        PoolStateSpec::UniswapV2 { .. } => ("uniswap_v2", HashMap::new()),
+        PoolStateSpec::UniswapV3 { fee, .. } => {
+            let fee = u32::try_from(*fee)
+                .map_err(|_| invalid(id, format!("unsupported fee tier {}", fee)))?;
+            let fee = Bytes::from(fee.to_be_bytes().to_vec());
+            ("uniswap_v3", HashMap::from([("fee".to_string(), fee)]))
+        }
+        PoolStateSpec::ScaleReserves { .. } => {
+            return Err(invalid(id, "synthetic pools need a full state, not scale_reserves"))
+        }
+    };
+    let chain = tokens[0].chain;
+    Ok(ProtocolComponent::new(
+        address,
+        protocol.to_string(),
+        format!("{}_pool", protocol),
+        chain,
+        tokens,
+        Vec::new(),
+        attributes,
+        Bytes::default(),
+        Default::default(),
+    ))
+}
+
+/// Copy of `snapshot` with the what-if changes applied and prices recomputed
+/// for every changed pool, then for every token in `numeraire` terms. Returns
+/// the ids of the changed pools alongside.
+pub fn apply_what_if(
+    snapshot: &BlockSnapshot,
+    what_if: &WhatIf,
+    numeraire: &str,
+) -> Result<(BlockSnapshot, Vec<String>), ApiError> {
+    let mut next = snapshot.clone();
+    let mut changed = Vec::new();
+
+    for change in &what_if.overrides {
+        let pool = change.pool.as_str();
+        if !next.components.contains_key(pool) {
+            return Err(ApiError::NotFound(format!("Pool not found: {}", pool)));
+        }
+        let current = next.states.get(pool).map(|state| state.as_ref());
+        let state = build_state(pool, &change.state, current)?;
+        next.states.insert(pool.to_string(), Arc::from(state));
+        changed.push(pool.to_string());
+    }
+
+    for synthetic in &what_if.synthetic_pools {
+        let id = synthetic.id.to_lowercase();
+        if next.components.contains_key(&id) {
+            return Err(invalid(&id, "a pool with this id already exists"));
+        }
+        let address = Bytes::from_str(&id).map_err(|_| invalid(&id, "id must be a hex address"))?;
+        let mut tokens: Vec<Token> = synthetic
+            .tokens
+            .iter()
+            .map(|token| {
+                next.find_token(token)
+                    .cloned()
+                    .ok_or_else(|| ApiError::NotFound(format!("Unknown token: {}", token)))
+            })
+            .collect::<Result<_, _>>()?;
+        if tokens.len() != 2 || tokens[0].address == tokens[1].address {
+            return Err(invalid(&id, "synthetic pools need two distinct tokens"));
+        }
+        tokens.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let component = synthetic_component(&id, address, tokens, &synthetic.state)?;
+        let state = build_state(&id, &synthetic.state, None)?;
+        next.components.insert(id.clone(), Arc::new(component));
+        next.states.insert(id.clone(), Arc::from(state));
+        changed.push(id);
+    }
+
+    let prices = compute_batch(&next, &changed);
+    if let Some((pool, reason)) = prices.failures.first() {
+        return Err(invalid(pool, format!("overridden state can't be priced: {}", reason)));
+    }
+    next.spot_prices.extend(prices.spot_prices);
+    next.pair_prices.extend(prices.pair_prices);
+    for pool in &changed {
+        next.release(pool);
+        let (Some(component), Some(state)) = (next.components.get(pool), next.states.get(pool)) else {
+            continue;
+        };
+        match fee_bps(state.as_ref(), component) {
+            Some(fee) => next.fees.insert(pool.clone(), fee),
+            None => next.fees.remove(pool),
+        };
+    }
+    // Gas costs and TVL estimates value tokens through the changed pools too
+    next.token_prices = Arc::new(numeraire_prices(&next, numeraire));
+    Ok((next, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::fixtures::{add_pool, address, component, token};
+
+    const SYNTHETIC: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn synthetic(tokens: &[&Token], state: PoolStateSpec) -> WhatIf {
+        WhatIf {
+            synthetic_pools: vec![SyntheticPool {
+                id: SYNTHETIC.to_string(),
+                tokens: tokens.iter().map(|token| address(token)).collect(),
+                state,
+            }],
+            ..WhatIf::default()
+        }
+    }
+
+    #[test]
+    fn takes_synthetic_pool_attributes_from_the_spec() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let spec = PoolStateSpec::UniswapV3 {
+            liquidity: "0".to_string(),
+            sqrt_price_x96: "0".to_string(),
+            tick: 0,
+            fee: 3000,
+            ticks: Vec::new(),
+        };
+        let address = Bytes::from_str(SYNTHETIC).unwrap();
+        let component = synthetic_component(SYNTHETIC, address.clone(), vec![usdc, weth], &spec).unwrap();
+        assert_eq!(component.protocol_system, "uniswap_v3");
+        assert_eq!(component.static_attributes.len(), 1);
+        assert_eq!(component.static_attributes["fee"], Bytes::from(3000u32.to_be_bytes().to_vec()));
+
+        let spec = PoolStateSpec::UniswapV2 {
+            reserve0: "1".to_string(),
+            reserve1: "1".to_string(),
+        };
+        let component =
+            synthetic_component(SYNTHETIC, address, vec![token(1, 6), token(2, 18)], &spec).unwrap();
+        assert_eq!(component.protocol_system, "uniswap_v2");
+        assert!(component.static_attributes.is_empty());
+    }
+
+    #[test]
+    fn rejects_synthetic_pools_without_a_full_state() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+
+        let what_if = synthetic(&[&usdc, &weth], PoolStateSpec::ScaleReserves { factor: 2.0 });
+        let result = apply_what_if(&snapshot, &what_if, &address(&usdc));
+        assert!(matches!(result, Err(ApiError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn prices_tokens_through_a_synthetic_pool() {
+        let (usdc, pepe) = (token(1, 6), token(3, 18));
+        let mut snapshot = BlockSnapshot::default();
+        // Pepe is known but has no priced pool yet
+        let known = component("pepe_usdc", "uniswap_v2", &[&usdc, &pepe]);
+        snapshot.components.insert("pepe_usdc".to_string(), Arc::new(known));
+
+        // 1M usdc against 1B pepe, a thousandth of a dollar each
+        let what_if = synthetic(
+            &[&pepe, &usdc],
+            PoolStateSpec::UniswapV2 {
+                reserve0: "1000000000000".to_string(),
+                reserve1: "1000000000000000000000000000".to_string(),
+            },
+        );
+        let (next, changed) = apply_what_if(&snapshot, &what_if, &address(&usdc)).unwrap();
+        assert_eq!(changed, vec![SYNTHETIC.to_string()]);
+        assert!(!snapshot.token_prices.contains_key(&address(&pepe)));
+        let price = next.token_prices[&address(&pepe)];
+        assert!((price - 0.001).abs() < 0.001 * 0.01, "{}", price);
+    }
+}
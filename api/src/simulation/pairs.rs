@@ -0,0 +1,202 @@
+use num_bigint::BigUint;
+use num_traits::Zero;
+use serde::Serialize;
+use tycho_simulation::tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim};
+
+use crate::errors::ApiError;
+
+use super::curve::log_amounts;
+use super::executor::CancelToken;
+use super::pricing::tvl_estimate;
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+use super::swap::{find_token, to_units};
+
+/// Bisection steps when searching for the depth of a pool
+const DEPTH_SEARCH_STEPS: usize = 24;
+
+/// How one pool on a pair compares to the others
+#[derive(Debug, Serialize)]
+pub struct PairPool {
+    pub pool: String,
+    pub protocol_system: String,
+    /// Spot price in quote per base
+    pub spot_price: Option<f64>,
+    /// Deviation of the spot price from the pair's TVL-weighted mid price
+    pub deviation_bps: Option<f64>,
+    /// Value the pool can pay out of its tokens, in the numeraire. Derived
+    /// from the pool's limits, so an estimate rather than on-chain balances.
+    pub tvl_estimate: Option<f64>,
+    /// Base tokens that can be sold before the price impact reaches the depth threshold
+    pub depth_base: Option<f64>,
+    /// Quote tokens that can be sold before the price impact reaches the depth threshold
+    pub depth_quote: Option<f64>,
+    /// Fee the pool currently charges, in basis points
+    pub fee_bps: Option<f64>,
+    /// Gas of a small base to quote swap
+    pub gas: Option<String>,
+    /// Why the pool couldn't be simulated, if it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairComparison {
+    pub block_number: u64,
+    pub base_token: String,
+    pub quote_token: String,
+    pub numeraire: String,
+    /// Spot prices weighted by estimated TVL, in quote per base
+    pub mid_price: Option<f64>,
+    pub total_tvl_estimate: f64,
+    /// Price impact, in percent, that depth is measured at
+    pub depth_impact_pct: f64,
+    pub pools: Vec<PairPool>,
+}
+
+/// Largest amount of `sell` the pool takes with at most `impact_pct` price
+/// impact, in whole tokens
+fn depth(
+    state: &dyn ProtocolSim,
+    sell: &Token,
+    buy: &Token,
+    max_sell: &BigUint,
+    spot_price: f64,
+    impact_pct: f64,
+) -> Option<f64> {
+    let impact_at = |amount: &BigUint| -> Option<f64> {
+        let result = isolate(|| state.get_amount_out(amount.clone(), sell, buy)).ok()?;
+        let effective = to_units(&result.amount, buy.decimals as u32)
+            / to_units(amount, sell.decimals as u32);
+        Some((spot_price - effective) / spot_price * 100.0)
+    };
+
+    let mut low = BigUint::zero();
+    let mut high = max_sell.clone();
+    if impact_at(&high).is_some_and(|impact| impact <= impact_pct) {
+        return Some(to_units(&high, sell.decimals as u32));
+    }
+    for _ in 0..DEPTH_SEARCH_STEPS {
+        let mid: BigUint = (&low + &high) / 2u32;
+        if mid <= low {
+            break;
+        }
+        match impact_at(&mid) {
+            Some(impact) if impact <= impact_pct => low = mid,
+            _ => high = mid,
+        }
+    }
+    Some(to_units(&low, sell.decimals as u32))
+}
+
+/// Compare every pool trading `base`/`quote`. Meant to run on a blocking worker.
+pub fn compare_pair(
+    snapshot: &BlockSnapshot,
+    numeraire: &str,
+    base: &str,
+    quote: &str,
+    depth_impact_pct: f64,
+    cancel: &CancelToken,
+) -> Result<PairComparison, ApiError> {
+    if base.eq_ignore_ascii_case(quote) {
+        return Err(ApiError::InvalidInput("Base and quote token must differ".to_string()));
+    }
+    let mut pools = Vec::new();
+    for (id, component) in &snapshot.components {
+        let (Some(base_token), Some(quote_token)) =
+            (find_token(component, base), find_token(component, quote))
+        else {
+            continue;
+        };
+        if cancel.is_cancelled() {
+            return Err(ApiError::Timeout(format!("Comparison of {} / {} abandoned", base, quote)));
+        }
+        let mut entry = PairPool {
+            pool: id.clone(),
+            protocol_system: component.protocol_system.clone(),
+            spot_price: None,
+            deviation_bps: None,
+            tvl_estimate: None,
+            depth_base: None,
+            depth_quote: None,
+            fee_bps: None,
+            gas: None,
+            error: None,
+        };
+        if let Some(quarantined) = snapshot.quarantined.get(id) {
+            entry.error = Some(format!("quarantined: {}", quarantined.reason));
+            pools.push(entry);
+            continue;
+        }
+        let Some(state) = snapshot.states.get(id).map(|state| state.as_ref()) else {
+            continue;
+        };
+
+        let limits = (
+            isolate(|| state.get_limits(base_token.address.clone(), quote_token.address.clone())),
+            isolate(|| state.get_limits(quote_token.address.clone(), base_token.address.clone())),
+        );
+        let (Ok((max_sell_base, _)), Ok((max_sell_quote, _))) = limits else {
+            entry.error = Some("pool limits unavailable".to_string());
+            pools.push(entry);
+            continue;
+        };
+
+        entry.spot_price = isolate(|| state.spot_price(base_token, quote_token)).ok();
+        entry.fee_bps = snapshot.fees.get(id).copied();
+        entry.gas = log_amounts(&max_sell_base, 1)
+            .first()
+            .and_then(|amount| isolate(|| state.get_amount_out(amount.clone(), base_token, quote_token)).ok())
+            .map(|result| result.gas.to_string());
+        entry.tvl_estimate = tvl_estimate(snapshot, state, &component.tokens);
+        if let Some(spot) = entry.spot_price.filter(|spot| *spot > 0.0) {
+            entry.depth_base =
+                depth(state, base_token, quote_token, &max_sell_base, spot, depth_impact_pct);
+            entry.depth_quote = depth(
+                state,
+                quote_token,
+                base_token,
+                &max_sell_quote,
+                1.0 / spot,
+                depth_impact_pct,
+            );
+        }
+        pools.push(entry);
+    }
+    if pools.is_empty() {
+        return Err(ApiError::NotFound(format!("No pools trade {} / {}", base, quote)));
+    }
+
+    // Pools without a TVL estimate don't weigh into the mid price
+    let weighted: Vec<(f64, f64)> = pools
+        .iter()
+        .filter_map(|pool| Some((pool.spot_price?, pool.tvl_estimate?)))
+        .filter(|(price, tvl)| price.is_finite() && *tvl > 0.0)
+        .collect();
+    let total_tvl_estimate: f64 = weighted.iter().map(|(_, tvl)| tvl).sum();
+    let mid_price = (total_tvl_estimate > 0.0).then(|| {
+        weighted.iter().map(|(price, tvl)| price * tvl).sum::<f64>() / total_tvl_estimate
+    });
+    if let Some(mid) = mid_price.filter(|mid| *mid > 0.0) {
+        for pool in &mut pools {
+            pool.deviation_bps = pool.spot_price.map(|price| (price - mid) / mid * 10_000.0);
+        }
+    }
+    pools.sort_by(|a, b| {
+        b.tvl_estimate
+            .unwrap_or(0.0)
+            .total_cmp(&a.tvl_estimate.unwrap_or(0.0))
+    });
+
+    Ok(PairComparison {
+        block_number: snapshot.block_number,
+        base_token: base.to_lowercase(),
+        quote_token: quote.to_lowercase(),
+        numeraire: numeraire.to_string(),
+        mid_price,
+        total_tvl_estimate,
+        depth_impact_pct,
+        pools,
+    })
+}
+
@@ -0,0 +1,345 @@
+use futures::{stream, StreamExt};
+use num_bigint::BigUint;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, warn};
+
+use crate::errors::ApiError;
+
+use super::executor::CancelToken;
+use super::router::{best_route, RouteOptions};
+use super::state::SimulationState;
+
+/// Most trades a single analysis may run
+pub const MAX_GRID_TRADES: usize = 500;
+
+/// How the trades of a grid are scheduled on the executor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridRun {
+    /// Periodic refresh: waits for free workers and runs to completion
+    Background,
+    /// On behalf of a request: subject to the executor's queue and job
+    /// timeouts, and abandoned if the request goes away
+    Request,
+}
+
+/// A token pair to trade in the grid, sell then buy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridPair {
+    pub sell_token: String,
+    pub buy_token: String,
+}
+
+/// Trades to run through the router: every pair at every size. Sizes are in
+/// numeraire units and spaced logarithmically between `min_size` and `max_size`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeGrid {
+    pub pairs: Vec<GridPair>,
+    pub min_size: f64,
+    pub max_size: f64,
+    pub steps: usize,
+}
+
+impl TradeGrid {
+    pub fn sizes(&self) -> Vec<f64> {
+        if self.steps <= 1 {
+            return vec![self.min_size];
+        }
+        let ratio = (self.max_size / self.min_size).ln() / (self.steps - 1) as f64;
+        (0..self.steps)
+            .map(|step| self.min_size * (ratio * step as f64).exp())
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.pairs.is_empty() {
+            return Err(ApiError::InvalidInput("At least one pair is required".to_string()));
+        }
+        if !(self.min_size > 0.0 && self.max_size >= self.min_size) {
+            return Err(ApiError::InvalidInput(
+                "Sizes must satisfy 0 < min_size <= max_size".to_string(),
+            ));
+        }
+        let trades = self.pairs.len() * self.steps.max(1);
+        if trades > MAX_GRID_TRADES {
+            return Err(ApiError::InvalidInput(format!(
+                "Grid of {} trades exceeds the limit of {}",
+                trades, MAX_GRID_TRADES
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How often a pool was on the best route
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolParticipation {
+    pub pool: String,
+    pub protocol_system: String,
+    pub tokens: Vec<String>,
+    /// Trades whose best route went through the pool
+    pub appearances: usize,
+    /// Share of routed trades that went through the pool
+    pub share: f64,
+    /// Trade sizes, in numeraire units, the pool was on the best route for
+    pub sizes: Vec<f64>,
+}
+
+/// How often any pool of a protocol was on the best route
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolParticipation {
+    pub protocol_system: String,
+    pub appearances: usize,
+    pub share: f64,
+    /// Distinct pools of the protocol that appeared
+    pub pools: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ParticipationReport {
+    pub block_number: u64,
+    pub generated_at: u64,
+    pub grid: TradeGrid,
+    pub numeraire: String,
+    /// Trades that found a route
+    pub routed: usize,
+    /// Trades skipped because the sell token has no price, or that found no route
+    pub skipped: usize,
+    pub pools: Vec<PoolParticipation>,
+    pub protocols: Vec<ProtocolParticipation>,
+}
+
+impl ParticipationReport {
+    /// Keep only pools that trade `token` and/or are `pool`
+    pub fn filtered(mut self, token: Option<&str>, pool: Option<&str>) -> Self {
+        self.pools.retain(|entry| {
+            token.is_none_or(|token| {
+                entry.tokens.iter().any(|t| t.eq_ignore_ascii_case(token))
+            }) && pool.is_none_or(|pool| entry.pool.eq_ignore_ascii_case(pool))
+        });
+        self
+    }
+}
+
+/// Run every trade of the grid through the router against the latest block.
+/// At most half the executor's workers are used at once, so a grid never
+/// crowds out other requests. A request run fails as soon as a trade can't be
+/// scheduled, which drops and cancels the rest of the grid.
+pub async fn analyze(
+    state: &SimulationState,
+    grid: &TradeGrid,
+    mode: GridRun,
+) -> Result<ParticipationReport, ApiError> {
+    let snapshot = state.snapshot();
+    let pricing = state.pricing().clone();
+    let sizes = grid.sizes();
+
+    let mut trades = Vec::new();
+    let mut skipped = 0;
+    for pair in &grid.pairs {
+        let sell = pair.sell_token.to_lowercase();
+        let (Some(token), Some(price)) = (
+            snapshot.find_token(&sell),
+            snapshot.token_prices.get(&sell).copied(),
+        ) else {
+            skipped += sizes.len();
+            continue;
+        };
+        for size in &sizes {
+            let amount = size / price * 10f64.powi(token.decimals as i32);
+            match BigUint::from_f64(amount) {
+                Some(amount) if amount > BigUint::from(0u32) => {
+                    trades.push((pair.clone(), *size, amount))
+                }
+                _ => skipped += 1,
+            }
+        }
+    }
+
+    // One job per trade so the grid spreads over the worker pool
+    let in_flight = (state.executor().max_concurrency() / 2).max(1);
+    let jobs = trades.into_iter().map(|(pair, size, amount)| {
+        let snapshot = snapshot.clone();
+        let pricing = pricing.clone();
+        async move {
+            let route = move |cancel: &CancelToken| {
+                best_route(
+                    &snapshot,
+                    &pricing,
+                    &pair.sell_token,
+                    &pair.buy_token,
+                    &amount,
+                    RouteOptions::default(),
+                    cancel,
+                )
+            };
+            let quote = match mode {
+                GridRun::Request => state.executor().run(route).await,
+                GridRun::Background => {
+                    state
+                        .executor()
+                        .run_to_completion(move || route(&CancelToken::default()))
+                        .await
+                }
+            };
+            (size, quote)
+        }
+    });
+    let mut results = stream::iter(jobs).buffer_unordered(in_flight);
+
+    let mut pools: HashMap<String, PoolParticipation> = HashMap::new();
+    let mut protocols: HashMap<String, (usize, HashSet<String>)> = HashMap::new();
+    let mut routed = 0;
+    while let Some((size, result)) = results.next().await {
+        let quote = match result {
+            Ok(Ok(quote)) => quote,
+            Ok(Err(e)) => {
+                debug!("Participation trade found no route: {}", e);
+                skipped += 1;
+                continue;
+            }
+            Err(e) if mode == GridRun::Request => return Err(e.into()),
+            Err(e) => {
+                warn!("Participation trade failed: {}", e);
+                skipped += 1;
+                continue;
+            }
+        };
+        routed += 1;
+
+        let mut seen_protocols = HashSet::new();
+        let route_pools: HashSet<&String> = quote.route.iter().map(|hop| &hop.pool).collect();
+        for pool in route_pools {
+            let Some(component) = snapshot.components.get(pool) else {
+                continue;
+            };
+            let entry = pools.entry(pool.clone()).or_insert_with(|| PoolParticipation {
+                pool: pool.clone(),
+                protocol_system: component.protocol_system.clone(),
+                tokens: component.tokens.iter().map(|t| t.address.to_string()).collect(),
+                appearances: 0,
+                share: 0.0,
+                sizes: Vec::new(),
+            });
+            entry.appearances += 1;
+            entry.sizes.push(size);
+
+            let protocol = protocols.entry(component.protocol_system.clone()).or_default();
+            protocol.1.insert(pool.clone());
+            if seen_protocols.insert(component.protocol_system.clone()) {
+                protocol.0 += 1;
+            }
+        }
+    }
+
+    let share = |appearances: usize| {
+        if routed == 0 {
+            0.0
+        } else {
+            appearances as f64 / routed as f64
+        }
+    };
+    let mut pools: Vec<PoolParticipation> = pools
+        .into_values()
+        .map(|mut entry| {
+            entry.share = share(entry.appearances);
+            entry.sizes.sort_by(|a, b| a.total_cmp(b));
+            entry
+        })
+        .collect();
+    pools.sort_by(|a, b| b.appearances.cmp(&a.appearances));
+    let mut protocols: Vec<ProtocolParticipation> = protocols
+        .into_iter()
+        .map(|(protocol_system, (appearances, pools))| ProtocolParticipation {
+            protocol_system,
+            appearances,
+            share: share(appearances),
+            pools: pools.len(),
+        })
+        .collect();
+    protocols.sort_by(|a, b| b.appearances.cmp(&a.appearances));
+
+    Ok(ParticipationReport {
+        block_number: snapshot.block_number,
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        grid: grid.clone(),
+        numeraire: pricing.numeraire,
+        routed,
+        skipped,
+        pools,
+        protocols,
+    })
+}
+
+/// Latest report of the background analysis, and whether a requested
+/// analysis is running
+#[derive(Debug, Clone, Default)]
+pub struct ParticipationTracker {
+    latest: Arc<RwLock<Option<ParticipationReport>>>,
+    running: Arc<AtomicBool>,
+}
+
+/// Marks a requested analysis as running until dropped
+pub struct RunGuard(Arc<AtomicBool>);
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl ParticipationTracker {
+    /// Claim the slot for a requested analysis, `None` if one is running
+    pub fn try_start(&self) -> Option<RunGuard> {
+        self.running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| RunGuard(self.running.clone()))
+    }
+
+    pub fn latest(&self) -> Option<ParticipationReport> {
+        self.latest.read().unwrap().clone()
+    }
+
+    /// Re-run the grid every `every_blocks` blocks for as long as the process lives
+    pub fn spawn(&self, state: SimulationState, grid: TradeGrid, every_blocks: u64) {
+        let latest = self.latest.clone();
+        tokio::spawn(async move {
+            let subscription = state.subscribe_to_updates();
+            let mut last_run = 0u64;
+            loop {
+                let update = subscription.outbox().recv().await;
+                // A reorg back below the last run makes it due again
+                let due = update.block_number < last_run
+                    || update.block_number >= last_run + every_blocks.max(1);
+                if !due {
+                    continue;
+                }
+                last_run = update.block_number;
+                let report = match analyze(&state, &grid, GridRun::Background).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        warn!("Participation analysis failed: {}", e);
+                        continue;
+                    }
+                };
+                info!(
+                    "Participation analysis at block {}: {} trades routed, {} skipped",
+                    report.block_number, report.routed, report.skipped
+                );
+                *latest.write().unwrap() = Some(report);
+            }
+        });
+    }
+}
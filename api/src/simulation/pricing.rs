@@ -0,0 +1,282 @@
+use num_bigint::BigUint;
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use tycho_simulation::tycho_core::{
+    models::{token::Token, Chain},
+    simulation::protocol_sim::ProtocolSim,
+};
+
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+use super::swap::to_units;
+
+/// Gas is paid in the chain's native token, which has 18 decimals everywhere we run
+const NATIVE_DECIMALS: i32 = 18;
+
+/// How gas is priced and which token prices are expressed in
+#[derive(Debug, Clone)]
+pub struct PricingConfig {
+    /// Gas price in wei, `None` disables gas cost conversion
+    pub gas_price_wei: Option<f64>,
+    /// Wrapped native token, used to value gas through the price graph
+    pub native_token: String,
+    /// Token every other token is priced in, e.g. USDC
+    pub numeraire: String,
+}
+
+/// Wrapped native token of a chain
+pub fn default_native_token(chain: Chain) -> Option<&'static str> {
+    match chain {
+        Chain::Ethereum => Some("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+        Chain::Base | Chain::Unichain => Some("0x4200000000000000000000000000000000000006"),
+        _ => None,
+    }
+}
+
+/// USDC on each chain
+pub fn default_numeraire(chain: Chain) -> Option<&'static str> {
+    match chain {
+        Chain::Ethereum => Some("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"),
+        Chain::Base => Some("0x833589fcd6edb6e08f4c7c32d4f71b54bda02913"),
+        Chain::Unichain => Some("0x078d782b760474a361dda0af3839290b0ef57ad6"),
+        _ => None,
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+}
+
+/// Price of every token reachable from the numeraire through the spot price
+/// graph, in numeraire units per whole token. Each token is priced along the
+/// path with the fewest hops, using the median rate across all pools of a pair
+/// so that a single thin pool can't skew it.
+pub fn numeraire_prices(snapshot: &BlockSnapshot, numeraire: &str) -> HashMap<String, f64> {
+    // rates[(a, b)]: how many b one a is worth, per pool
+    let mut rates: HashMap<(&str, &str), Vec<f64>> = HashMap::new();
+    for (pool, pairs) in &snapshot.pair_prices {
+        if snapshot.quarantined.contains_key(pool) {
+            continue;
+        }
+        for pair in pairs {
+            if pair.price <= 0.0 {
+                continue;
+            }
+            rates
+                .entry((pair.base.as_str(), pair.quote.as_str()))
+                .or_default()
+                .push(pair.price);
+            rates
+                .entry((pair.quote.as_str(), pair.base.as_str()))
+                .or_default()
+                .push(1.0 / pair.price);
+        }
+    }
+
+    // Edges into each token: (token, rate of token in the target)
+    let mut incoming: HashMap<&str, Vec<(&str, f64)>> = HashMap::new();
+    for ((from, to), mut values) in rates {
+        incoming.entry(to).or_default().push((from, median(&mut values)));
+    }
+
+    let mut prices = HashMap::new();
+    prices.insert(numeraire.to_string(), 1.0);
+    let mut queue = VecDeque::from([numeraire]);
+    while let Some(token) = queue.pop_front() {
+        let price = prices[token];
+        for (neighbour, rate) in incoming.get(token).into_iter().flatten() {
+            if !prices.contains_key(*neighbour) {
+                prices.insert(neighbour.to_string(), rate * price);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    prices
+}
+
+/// Gas cost of a route expressed in several units
+#[derive(Debug, Clone, Serialize)]
+pub struct GasCost {
+    pub gas_units: String,
+    pub gas_price_gwei: f64,
+    /// Cost in whole native tokens (ETH)
+    pub native: f64,
+    /// Cost in whole units of the route's output token, if it can be priced
+    pub output_token: Option<f64>,
+    /// Cost in whole numeraire tokens, if the native token can be priced
+    pub numeraire: Option<f64>,
+    pub numeraire_token: String,
+}
+
+/// Value a pool can pay out of all its tokens, in the numeraire. Derived from
+/// the pool's limits, so an estimate rather than on-chain balances.
+pub fn tvl_estimate(snapshot: &BlockSnapshot, state: &dyn ProtocolSim, tokens: &[Token]) -> Option<f64> {
+    if tokens.len() < 2 {
+        return None;
+    }
+    let mut total = 0.0;
+    for (i, token) in tokens.iter().enumerate() {
+        // Selling any other token drains the pool of this one, the next will do
+        let other = &tokens[(i + 1) % tokens.len()];
+        let price = snapshot.token_prices.get(&token.address.to_string())?;
+        let (_, max_buy) =
+            isolate(|| state.get_limits(other.address.clone(), token.address.clone())).ok()?;
+        total += to_units(&max_buy, token.decimals as u32) * price;
+    }
+    Some(total)
+}
+
+impl GasCost {
+    /// Gas cost in base units of the output token
+    pub fn output_token_raw(&self, output_decimals: u32) -> Option<BigUint> {
+        self.output_token
+            .map(|cost| cost * 10f64.powi(output_decimals as i32))
+            .and_then(BigUint::from_f64)
+    }
+}
+
+impl PricingConfig {
+    /// Value `gas` at the configured gas price, in native, numeraire and
+    /// output token terms. Returns `None` when no gas price is configured.
+    pub fn gas_cost(
+        &self,
+        snapshot: &BlockSnapshot,
+        gas: &BigUint,
+        output_token: &Token,
+    ) -> Option<GasCost> {
+        let gas_price_wei = self.gas_price_wei?;
+        let native = gas.to_f64()? * gas_price_wei / 10f64.powi(NATIVE_DECIMALS);
+        let native_price = snapshot.token_prices.get(&self.native_token).copied();
+        let numeraire = native_price.map(|price| native * price);
+        let output_address = output_token.address.to_string();
+        let output_cost = if output_address.eq_ignore_ascii_case(&self.native_token) {
+            // Gas is already paid in the output token, no price is needed
+            Some(native)
+        } else {
+            let output_price = snapshot
+                .token_prices
+                .get(&output_address)
+                .copied()
+                .filter(|price| *price > 0.0);
+            match (numeraire, output_price) {
+                (Some(numeraire), Some(price)) => Some(numeraire / price),
+                _ => None,
+            }
+        };
+        Some(GasCost {
+            gas_units: gas.to_string(),
+            gas_price_gwei: gas_price_wei / 1e9,
+            native,
+            output_token: output_cost,
+            numeraire,
+            numeraire_token: self.numeraire.clone(),
+        })
+    }
+
+    /// Output left after paying for gas, in base units of the output token.
+    /// `None` if gas can't be valued in the output token.
+    pub fn net_amount_out(
+        &self,
+        gas_cost: Option<&GasCost>,
+        amount_out: &BigUint,
+        output_token: &Token,
+    ) -> Option<BigUint> {
+        let cost = gas_cost?.output_token_raw(output_token.decimals as u32)?;
+        Some(if &cost >= amount_out {
+            BigUint::from(0u32)
+        } else {
+            amount_out - cost
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::fixtures::{add_pool, address, token};
+    use crate::simulation::quarantine::QuarantineEntry;
+
+    fn config(native: &Token, numeraire: &Token) -> PricingConfig {
+        PricingConfig {
+            gas_price_wei: Some(10e9),
+            native_token: address(native),
+            numeraire: address(numeraire),
+        }
+    }
+
+    #[test]
+    fn prices_tokens_along_the_shortest_path() {
+        let (usdc, weth, wbtc, pepe) = (token(1, 6), token(2, 18), token(3, 8), token(4, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        add_pool(&mut snapshot, "wbtc_weth", &wbtc, &weth, 30.0);
+        // Quoted the other way round: one usdc buys 1000 pepe
+        add_pool(&mut snapshot, "usdc_pepe", &usdc, &pepe, 1000.0);
+
+        let prices = numeraire_prices(&snapshot, &address(&usdc));
+        assert_eq!(prices[&address(&usdc)], 1.0);
+        assert_eq!(prices[&address(&weth)], 2000.0);
+        assert_eq!(prices[&address(&wbtc)], 60000.0);
+        assert!((prices[&address(&pepe)] - 0.001).abs() < 1e-12);
+    }
+
+    #[test]
+    fn prices_a_pair_at_its_median_rate() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "a", &weth, &usdc, 1990.0);
+        add_pool(&mut snapshot, "b", &weth, &usdc, 2000.0);
+        // A thin pool far off the market doesn't move the price
+        add_pool(&mut snapshot, "c", &weth, &usdc, 10.0);
+
+        let prices = numeraire_prices(&snapshot, &address(&usdc));
+        assert_eq!(prices[&address(&weth)], 1990.0);
+    }
+
+    #[test]
+    fn leaves_unreachable_and_quarantined_tokens_unpriced() {
+        let (usdc, weth, dai, frax) = (token(1, 6), token(2, 18), token(3, 18), token(4, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        add_pool(&mut snapshot, "dai_frax", &dai, &frax, 1.0);
+        snapshot.quarantined.insert(
+            "weth_usdc".to_string(),
+            QuarantineEntry::new("weth_usdc", "uniswap_v2", "panicked".to_string(), 1),
+        );
+
+        let prices = numeraire_prices(&snapshot, &address(&usdc));
+        assert_eq!(prices.len(), 1);
+        assert!(!prices.contains_key(&address(&dai)));
+    }
+
+    #[test]
+    fn values_gas_in_the_output_token() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        snapshot.token_prices = std::sync::Arc::new(numeraire_prices(&snapshot, &address(&usdc)));
+
+        // 100k gas at 10 gwei is 0.001 eth
+        let gas = BigUint::from(100_000u32);
+        let cost = config(&weth, &usdc).gas_cost(&snapshot, &gas, &usdc).unwrap();
+        assert!((cost.native - 0.001).abs() < 1e-12);
+        assert!((cost.numeraire.unwrap() - 2.0).abs() < 1e-9);
+        assert!((cost.output_token.unwrap() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn values_gas_in_the_native_token_without_prices() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let snapshot = BlockSnapshot::default();
+
+        let gas = BigUint::from(100_000u32);
+        let cost = config(&weth, &usdc).gas_cost(&snapshot, &gas, &weth).unwrap();
+        assert_eq!(cost.numeraire, None);
+        assert!((cost.output_token.unwrap() - 0.001).abs() < 1e-12);
+        // Other output tokens need the numeraire price
+        let cost = config(&weth, &usdc).gas_cost(&snapshot, &gas, &usdc).unwrap();
+        assert_eq!(cost.output_token, None);
+    }
+}
@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A pool taken out of service because simulating it failed
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantineEntry {
+    pub pool: String,
+    pub protocol_system: String,
+    pub reason: String,
+    /// Block in which the pool first failed
+    pub since_block: u64,
+    /// Block of the most recent failure
+    pub last_failed_block: u64,
+    pub failures: u32,
+    /// Unix timestamp of the first failure
+    pub quarantined_at: u64,
+}
+
+impl QuarantineEntry {
+    pub fn new(pool: &str, protocol_system: &str, reason: String, block_number: u64) -> Self {
+        QuarantineEntry {
+            pool: pool.to_string(),
+            protocol_system: protocol_system.to_string(),
+            reason,
+            since_block: block_number,
+            last_failed_block: block_number,
+            failures: 1,
+            quarantined_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Record another failure of an already quarantined pool
+    pub fn failed_again(&mut self, reason: String, block_number: u64) {
+        self.reason = reason;
+        self.last_failed_block = block_number;
+        self.failures += 1;
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run a fallible per-pool computation, turning both errors and panics into
+/// a printable reason so one broken pool can't take down its caller
+pub fn isolate<T, E, F>(f: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, E>,
+    E: std::fmt::Display,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(payload) => Err(format!("panicked: {}", panic_message(payload))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::simulation::fixtures::{add_pool, address, token};
+    use crate::simulation::router::{candidate_routes, RouteOptions};
+    use crate::simulation::state::BlockSnapshot;
+    use crate::simulation::swap::{resolve_path, HopRequest};
+
+    /// Stands in for a `get_amount_out` that panics on a broken state
+    fn panicking_amount_out() -> Result<u64, String> {
+        panic!("attempt to divide by zero")
+    }
+
+    #[test]
+    fn passes_values_and_errors_through() {
+        assert_eq!(isolate(|| Ok::<_, String>(7)), Ok(7));
+        assert_eq!(isolate(|| Err::<u64, _>("no liquidity")), Err("no liquidity".to_string()));
+    }
+
+    #[test]
+    fn turns_panics_into_reasons() {
+        assert_eq!(
+            isolate(panicking_amount_out),
+            Err("panicked: attempt to divide by zero".to_string())
+        );
+        let tick = 5;
+        let formatted = isolate(|| -> Result<(), String> { panic!("tick {} out of range", tick) });
+        assert_eq!(formatted, Err("panicked: tick 5 out of range".to_string()));
+        let opaque = isolate(|| -> Result<(), String> { std::panic::panic_any(42) });
+        assert_eq!(opaque, Err("panicked: unknown panic".to_string()));
+    }
+
+    #[test]
+    fn quarantines_a_panicking_pool_until_it_simulates_again() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        let (sell, buy) = (address(&weth), address(&usdc));
+        let hops = [HopRequest {
+            pool: "weth_usdc".to_string(),
+            token_in: None,
+            token_out: None,
+        }];
+
+        let reason = isolate(panicking_amount_out).unwrap_err();
+        snapshot.quarantine("weth_usdc", reason.clone(), 10);
+        let reason_again = isolate(panicking_amount_out).unwrap_err();
+        snapshot.quarantine("weth_usdc", reason_again, 11);
+
+        let entry = &snapshot.quarantined["weth_usdc"];
+        assert_eq!(entry.protocol_system, "uniswap_v2");
+        assert_eq!((entry.since_block, entry.last_failed_block, entry.failures), (10, 11, 2));
+        assert!(!snapshot.spot_prices.contains_key("weth_usdc"));
+        assert!(candidate_routes(&snapshot, &sell, &buy, RouteOptions::default()).is_empty());
+        match resolve_path(&snapshot, Some(&sell), &hops) {
+            Err(ApiError::PoolUnavailable { hop, message, .. }) => {
+                assert_eq!(hop, 0);
+                assert!(message.contains(&reason), "{}", message);
+            }
+            other => panic!("expected the pool to be unavailable, got {:?}", other.map(|_| ())),
+        }
+
+        // The next block prices it fine again
+        let entry = snapshot.release("weth_usdc").unwrap();
+        assert_eq!(entry.failures, 2);
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2001.0);
+        assert!(snapshot.quarantined.is_empty());
+        assert_eq!(candidate_routes(&snapshot, &sell, &buy, RouteOptions::default()).len(), 1);
+        assert!(snapshot.release("weth_usdc").is_none());
+    }
+}
@@ -0,0 +1,464 @@
+use num_bigint::BigUint;
+use std::collections::HashMap;
+use tracing::debug;
+use tycho_simulation::tycho_core::models::token::Token;
+
+use crate::errors::ApiError;
+
+use super::executor::CancelToken;
+use super::pricing::{GasCost, PricingConfig};
+use super::state::BlockSnapshot;
+use super::swap::{simulate_path, LimitMode, ResolvedHop, RouteOutcome};
+
+/// Limits on how many routes are looked at for a quote
+#[derive(Debug, Clone, Copy)]
+pub struct RouteOptions {
+    /// Longest route considered
+    pub max_hops: usize,
+    /// Routes simulated, picked by their spot rate
+    pub max_candidates: usize,
+    /// Most connected tokens allowed as intermediate hops
+    pub connectors: usize,
+    /// Pools per token pair kept, picked by their spot rate
+    pub pools_per_pair: usize,
+}
+
+impl Default for RouteOptions {
+    fn default() -> Self {
+        RouteOptions {
+            max_hops: 3,
+            max_candidates: 20,
+            connectors: 20,
+            pools_per_pair: 5,
+        }
+    }
+}
+
+/// Best route found for a trade, with its gas cost
+#[derive(Debug)]
+pub struct Quote {
+    pub route: Vec<ResolvedHop>,
+    pub outcome: RouteOutcome,
+    pub gas_cost: Option<GasCost>,
+    /// Output after paying for gas, if gas could be valued in the output token
+    pub net_amount_out: Option<BigUint>,
+    /// Number of routes that were simulated successfully
+    pub simulated: usize,
+}
+
+/// A pool that swaps one token for another at its current spot rate
+#[derive(Debug, Clone)]
+struct Edge<'a> {
+    pool: &'a str,
+    token_in: &'a Token,
+    token_out: &'a Token,
+    token_out_address: String,
+    rate: f64,
+}
+
+impl Edge<'_> {
+    fn to_hop(&self) -> ResolvedHop {
+        ResolvedHop {
+            pool: self.pool.to_string(),
+            token_in: self.token_in.clone(),
+            token_out: self.token_out.clone(),
+        }
+    }
+}
+
+/// Rate of `token_out` per `token_in` in a pool, from its pair prices
+fn pair_rate(snapshot: &BlockSnapshot, pool: &str, token_in: &str, token_out: &str) -> Option<f64> {
+    snapshot.pair_prices.get(pool)?.iter().find_map(|pair| {
+        if pair.base == token_in && pair.quote == token_out {
+            Some(pair.price)
+        } else if pair.base == token_out && pair.quote == token_in && pair.price > 0.0 {
+            Some(1.0 / pair.price)
+        } else {
+            None
+        }
+    })
+}
+
+/// Every priced, non-quarantined swap direction keyed by input token, keeping
+/// only the best `pools_per_pair` pools of each pair
+fn build_edges(snapshot: &BlockSnapshot, pools_per_pair: usize) -> HashMap<String, Vec<Edge<'_>>> {
+    let mut by_pair: HashMap<(String, String), Vec<Edge>> = HashMap::new();
+    for (pool, component) in &snapshot.components {
+        // Pools only have pair prices while they have a state, see `pair_rate`
+        if snapshot.quarantined.contains_key(pool) {
+            continue;
+        }
+        for token_in in &component.tokens {
+            let address_in = token_in.address.to_string();
+            for token_out in &component.tokens {
+                if token_in.address == token_out.address {
+                    continue;
+                }
+                let address_out = token_out.address.to_string();
+                let Some(rate) = pair_rate(snapshot, pool, &address_in, &address_out)
+                    .filter(|rate| rate.is_finite() && *rate > 0.0)
+                else {
+                    continue;
+                };
+                by_pair
+                    .entry((address_in.clone(), address_out.clone()))
+                    .or_default()
+                    .push(Edge {
+                        pool,
+                        token_in,
+                        token_out,
+                        token_out_address: address_out,
+                        rate,
+                    });
+            }
+        }
+    }
+
+    let mut edges: HashMap<String, Vec<Edge>> = HashMap::new();
+    for ((token_in, _), mut pools) in by_pair {
+        pools.sort_by(|a, b| b.rate.total_cmp(&a.rate));
+        pools.truncate(pools_per_pair);
+        edges.entry(token_in).or_default().extend(pools);
+    }
+    edges
+}
+
+/// The `count` tokens with the most outgoing edges, which multi-hop routes
+/// and cycles may pass through
+fn most_connected<'e>(edges: &'e HashMap<String, Vec<Edge<'_>>>, count: usize) -> Vec<&'e str> {
+    let mut by_degree: Vec<(&String, usize)> =
+        edges.iter().map(|(token, out)| (token, out.len())).collect();
+    by_degree.sort_by(|a, b| b.1.cmp(&a.1));
+    by_degree
+        .into_iter()
+        .take(count)
+        .map(|(token, _)| token.as_str())
+        .collect()
+}
+
+/// Candidate routes from `sell` to `buy`, best spot rate first. Intermediate
+/// tokens are limited to the most connected ones, which is where liquidity
+/// for multi-hop routes sits in practice.
+pub fn candidate_routes(
+    snapshot: &BlockSnapshot,
+    sell: &str,
+    buy: &str,
+    options: RouteOptions,
+) -> Vec<Vec<ResolvedHop>> {
+    let edges = build_edges(snapshot, options.pools_per_pair);
+    let connectors = most_connected(&edges, options.connectors);
+
+    let mut found: Vec<(f64, Vec<&Edge>)> = Vec::new();
+    let mut path: Vec<&Edge> = Vec::new();
+    let mut visited = vec![sell.to_string()];
+    extend_routes(&edges, &connectors, buy, options.max_hops, 1.0, &mut path, &mut visited, &mut found);
+
+    found.sort_by(|a, b| b.0.total_cmp(&a.0));
+    found.truncate(options.max_candidates);
+    found
+        .into_iter()
+        .map(|(_, route)| route.into_iter().map(Edge::to_hop).collect())
+        .collect()
+}
+
+/// Depth-first search over the edges out of the last visited token
+#[allow(clippy::too_many_arguments)]
+fn extend_routes<'e, 'a>(
+    edges: &'e HashMap<String, Vec<Edge<'a>>>,
+    connectors: &[&str],
+    buy: &str,
+    hops_left: usize,
+    rate: f64,
+    path: &mut Vec<&'e Edge<'a>>,
+    visited: &mut Vec<String>,
+    found: &mut Vec<(f64, Vec<&'e Edge<'a>>)>,
+) {
+    if hops_left == 0 {
+        return;
+    }
+    let current = visited.last().expect("route starts at the sell token").clone();
+    for edge in edges.get(&current).into_iter().flatten() {
+        let next = edge.token_out_address.as_str();
+        let rate = rate * edge.rate;
+        if next == buy {
+            let mut route = path.clone();
+            route.push(edge);
+            found.push((rate, route));
+            continue;
+        }
+        if hops_left == 1
+            || !connectors.contains(&next)
+            || visited.iter().any(|token| token == next)
+        {
+            continue;
+        }
+        path.push(edge);
+        visited.push(next.to_string());
+        extend_routes(edges, connectors, buy, hops_left - 1, rate, path, visited, found);
+        visited.pop();
+        path.pop();
+    }
+}
+
+/// Cycles through the most connected tokens whose spot rates multiply to
+/// more than `min_rate`, best first. Each cycle is listed once, starting from
+/// its lowest token address, and uses a pool at most once.
+pub fn candidate_cycles(
+    snapshot: &BlockSnapshot,
+    max_length: usize,
+    min_rate: f64,
+    options: RouteOptions,
+) -> Vec<(f64, Vec<ResolvedHop>)> {
+    let edges = build_edges(snapshot, options.pools_per_pair);
+    let connectors = most_connected(&edges, options.connectors);
+
+    let mut found: Vec<(f64, Vec<&Edge>)> = Vec::new();
+    for start in &connectors {
+        let mut path: Vec<&Edge> = Vec::new();
+        extend_cycles(&edges, &connectors, start, max_length, 1.0, &mut path, &mut found);
+    }
+    found.retain(|(rate, _)| *rate > min_rate);
+    found.sort_by(|a, b| b.0.total_cmp(&a.0));
+    found.truncate(options.max_candidates);
+    found
+        .into_iter()
+        .map(|(rate, cycle)| (rate, cycle.into_iter().map(Edge::to_hop).collect()))
+        .collect()
+}
+
+/// Depth-first search for paths leading back to `start`
+fn extend_cycles<'e, 'a>(
+    edges: &'e HashMap<String, Vec<Edge<'a>>>,
+    connectors: &[&str],
+    start: &str,
+    hops_left: usize,
+    rate: f64,
+    path: &mut Vec<&'e Edge<'a>>,
+    found: &mut Vec<(f64, Vec<&'e Edge<'a>>)>,
+) {
+    if hops_left == 0 {
+        return;
+    }
+    let current = path.last().map_or(start, |edge| edge.token_out_address.as_str());
+    for edge in edges.get(current).into_iter().flatten() {
+        if path.iter().any(|used| used.pool == edge.pool) {
+            continue;
+        }
+        let next = edge.token_out_address.as_str();
+        let rate = rate * edge.rate;
+        if next == start {
+            if !path.is_empty() {
+                let mut cycle = path.clone();
+                cycle.push(edge);
+                found.push((rate, cycle));
+            }
+            continue;
+        }
+        // Tokens below the start are covered by the cycle starting there
+        if hops_left == 1
+            || next < start
+            || !connectors.contains(&next)
+            || path.iter().any(|used| used.token_out_address == next)
+        {
+            continue;
+        }
+        path.push(edge);
+        extend_cycles(edges, connectors, start, hops_left - 1, rate, path, found);
+        path.pop();
+    }
+}
+
+/// Simulate the candidate routes for a trade and pick the one with the most
+/// output after gas. Falls back to gross output when gas can't be valued in
+/// the output token. Meant to run on a blocking worker.
+pub fn best_route(
+    snapshot: &BlockSnapshot,
+    pricing: &PricingConfig,
+    sell: &str,
+    buy: &str,
+    amount_in: &BigUint,
+    options: RouteOptions,
+    cancel: &CancelToken,
+) -> Result<Quote, ApiError> {
+    let sell = sell.to_lowercase();
+    let buy = buy.to_lowercase();
+    if sell == buy {
+        return Err(ApiError::InvalidInput("Cannot swap a token for itself".to_string()));
+    }
+    let candidates = candidate_routes(snapshot, &sell, &buy, options);
+    if candidates.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "No route from {} to {} within {} hops",
+            sell, buy, options.max_hops
+        )));
+    }
+
+    let mut best: Option<Quote> = None;
+    let mut simulated = 0;
+    for route in candidates {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let outcome = match simulate_path(snapshot, &route, amount_in.clone(), LimitMode::Enforce, cancel) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                debug!("Skipping route through {:?}: {}", route.iter().map(|hop| &hop.pool).collect::<Vec<_>>(), e);
+                continue;
+            }
+        };
+        simulated += 1;
+
+        let token_out = &route.last().expect("routes are never empty").token_out;
+        let gas_cost = pricing.gas_cost(snapshot, &outcome.total_gas, token_out);
+        let net_amount_out = pricing.net_amount_out(gas_cost.as_ref(), &outcome.amount_out, token_out);
+        let candidate = Quote {
+            route,
+            outcome,
+            gas_cost,
+            net_amount_out,
+            simulated: 0,
+        };
+        if best.as_ref().is_none_or(|best| candidate.value() > best.value()) {
+            best = Some(candidate);
+        }
+    }
+
+    let mut best = match best {
+        Some(best) => best,
+        None if cancel.is_cancelled() => {
+            return Err(ApiError::Timeout(format!(
+                "Routing from {} to {} abandoned before any route finished",
+                sell, buy
+            )))
+        }
+        None => {
+            return Err(ApiError::SimulationError(format!(
+                "Every route from {} to {} failed to simulate",
+                sell, buy
+            )))
+        }
+    };
+    best.simulated = simulated;
+    Ok(best)
+}
+
+impl Quote {
+    /// What routes are ranked by: net output when known, gross otherwise
+    fn value(&self) -> &BigUint {
+        self.net_amount_out.as_ref().unwrap_or(&self.outcome.amount_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::fixtures::{add_pool, address, token};
+
+    fn pools(hops: &[ResolvedHop]) -> Vec<&str> {
+        hops.iter().map(|hop| hop.pool.as_str()).collect()
+    }
+
+    #[test]
+    fn ranks_routes_by_their_spot_rate() {
+        let (usdc, weth, dai) = (token(1, 6), token(2, 18), token(3, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        add_pool(&mut snapshot, "weth_dai", &weth, &dai, 2010.0);
+        add_pool(&mut snapshot, "dai_usdc", &dai, &usdc, 1.0);
+
+        let routes = candidate_routes(&snapshot, &address(&weth), &address(&usdc), RouteOptions::default());
+        let routes: Vec<Vec<&str>> = routes.iter().map(|route| pools(route)).collect();
+        assert_eq!(routes, vec![vec!["weth_dai", "dai_usdc"], vec!["weth_usdc"]]);
+
+        let direct = RouteOptions { max_hops: 1, ..RouteOptions::default() };
+        let routes = candidate_routes(&snapshot, &address(&weth), &address(&usdc), direct);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(address(&routes[0][0].token_out), address(&usdc));
+    }
+
+    #[test]
+    fn keeps_the_best_pools_of_a_pair() {
+        let (usdc, weth) = (token(1, 6), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "low", &weth, &usdc, 1990.0);
+        add_pool(&mut snapshot, "high", &weth, &usdc, 2000.0);
+        add_pool(&mut snapshot, "mid", &weth, &usdc, 1995.0);
+
+        let options = RouteOptions { pools_per_pair: 2, ..RouteOptions::default() };
+        let routes = candidate_routes(&snapshot, &address(&weth), &address(&usdc), options);
+        let routes: Vec<Vec<&str>> = routes.iter().map(|route| pools(route)).collect();
+        assert_eq!(routes, vec![vec!["high"], vec!["mid"]]);
+    }
+
+    #[test]
+    fn only_routes_through_connectors() {
+        let (usdc, weth, dai, pepe) = (token(1, 6), token(2, 18), token(3, 18), token(4, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "weth_usdc", &weth, &usdc, 2000.0);
+        add_pool(&mut snapshot, "weth_dai", &weth, &dai, 2000.0);
+        add_pool(&mut snapshot, "dai_usdc", &dai, &usdc, 1.0);
+        add_pool(&mut snapshot, "pepe_weth", &pepe, &weth, 0.001);
+
+        // weth has the most pools, usdc and dai tie behind it
+        let options = RouteOptions { connectors: 1, ..RouteOptions::default() };
+        let routes = candidate_routes(&snapshot, &address(&pepe), &address(&usdc), options);
+        let routes: Vec<Vec<&str>> = routes.iter().map(|route| pools(route)).collect();
+        assert_eq!(routes, vec![vec!["pepe_weth", "weth_usdc"]]);
+    }
+
+    #[test]
+    fn finds_each_profitable_cycle_once() {
+        let (a, b, c) = (token(1, 18), token(2, 18), token(3, 18));
+        let mut snapshot = BlockSnapshot::default();
+        // a -> b -> c -> a multiplies to 2 * 3 * 0.17 = 1.02
+        add_pool(&mut snapshot, "ab", &a, &b, 2.0);
+        add_pool(&mut snapshot, "bc", &b, &c, 3.0);
+        add_pool(&mut snapshot, "ca", &c, &a, 0.17);
+
+        let cycles = candidate_cycles(&snapshot, 3, 1.0, RouteOptions::default());
+        assert_eq!(cycles.len(), 1);
+        let (rate, cycle) = &cycles[0];
+        assert!((rate - 1.02).abs() < 1e-9);
+        // Listed from its lowest token
+        assert_eq!(address(&cycle[0].token_in), address(&a));
+        assert_eq!(pools(cycle), vec!["ab", "bc", "ca"]);
+
+        assert!(candidate_cycles(&snapshot, 3, 1.05, RouteOptions::default()).is_empty());
+        // Too long for two hops
+        assert!(candidate_cycles(&snapshot, 2, 1.0, RouteOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn finds_cycles_between_pools_of_one_pair() {
+        let (a, b) = (token(1, 18), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "low", &a, &b, 100.0);
+        add_pool(&mut snapshot, "high", &a, &b, 102.0);
+
+        let cycles = candidate_cycles(&snapshot, 2, 1.0, RouteOptions::default());
+        assert_eq!(cycles.len(), 1);
+        let (rate, cycle) = &cycles[0];
+        // Sell into the high pool, buy back from the low one
+        assert_eq!(pools(cycle), vec!["high", "low"]);
+        assert!((rate - 1.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skips_quarantined_pools_in_cycles() {
+        let (a, b) = (token(1, 18), token(2, 18));
+        let mut snapshot = BlockSnapshot::default();
+        add_pool(&mut snapshot, "low", &a, &b, 100.0);
+        add_pool(&mut snapshot, "high", &a, &b, 102.0);
+        snapshot.quarantined.insert(
+            "high".to_string(),
+            crate::simulation::quarantine::QuarantineEntry::new(
+                "high",
+                "uniswap_v2",
+                "panicked".to_string(),
+                1,
+            ),
+        );
+
+        assert!(candidate_cycles(&snapshot, 2, 0.0, RouteOptions::default()).is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+use num_bigint::BigUint;
+use serde::Serialize;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+use crate::errors::ApiError;
+
+use super::executor::CancelToken;
+use super::spot_prices::compute_batch;
+use super::state::BlockSnapshot;
+use super::swap::{simulate_path, LimitMode, ResolvedHop, RouteOutcome};
+
+/// Limits on simulation sandboxes
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxConfig {
+    /// Sandboxes alive at once
+    pub max_sandboxes: usize,
+    /// Sandboxes unused for this long are discarded
+    pub idle_timeout: Duration,
+}
+
+/// A private copy of one block's pool states that swaps are applied to, so a
+/// sequence of trades each sees the pools as the previous ones left them
+#[derive(Debug)]
+pub struct Sandbox {
+    pub id: String,
+    pub created_at: SystemTime,
+    /// Block whose states the sandbox was forked from
+    pub forked_from_block: u64,
+    pub snapshot: Arc<BlockSnapshot>,
+    pub swaps: Vec<SandboxSwap>,
+    /// Pools whose state was overridden or that were added by hand
+    pub overridden_pools: Vec<String>,
+}
+
+/// A swap applied to a sandbox
+#[derive(Debug, Clone, Serialize)]
+pub struct SandboxSwap {
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_in: String,
+    pub amount_out: String,
+    pub gas: String,
+    pub pools: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SandboxSummary {
+    pub id: String,
+    pub created_at: u64,
+    pub forked_from_block: u64,
+    pub swaps: Vec<SandboxSwap>,
+    pub overridden_pools: Vec<String>,
+    /// Current spot price of every pool the swaps or overrides changed
+    pub touched_pools: HashMap<String, Option<f64>>,
+}
+
+impl Sandbox {
+    /// Replace the sandbox state with the result of a swap and record it
+    pub fn apply(&mut self, route: &[ResolvedHop], outcome: &RouteOutcome, next: BlockSnapshot) {
+        self.snapshot = Arc::new(next);
+        self.swaps.push(SandboxSwap {
+            token_in: route[0].token_in.address.to_string(),
+            token_out: route[route.len() - 1].token_out.address.to_string(),
+            amount_in: outcome.amount_in.to_string(),
+            amount_out: outcome.amount_out.to_string(),
+            gas: outcome.total_gas.to_string(),
+            pools: route.iter().map(|hop| hop.pool.clone()).collect(),
+        });
+    }
+
+    /// Replace the sandbox state with one that has overrides applied
+    pub fn apply_overrides(&mut self, next: BlockSnapshot, changed: Vec<String>) {
+        self.snapshot = Arc::new(next);
+        for pool in changed {
+            if !self.overridden_pools.contains(&pool) {
+                self.overridden_pools.push(pool);
+            }
+        }
+    }
+
+    pub fn summary(&self) -> SandboxSummary {
+        let touched_pools = self
+            .swaps
+            .iter()
+            .flat_map(|swap| swap.pools.iter())
+            .chain(self.overridden_pools.iter())
+            .map(|pool| (pool.clone(), self.snapshot.spot_prices.get(pool).copied()))
+            .collect();
+        SandboxSummary {
+            id: self.id.clone(),
+            created_at: self
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            forked_from_block: self.forked_from_block,
+            swaps: self.swaps.clone(),
+            overridden_pools: self.overridden_pools.clone(),
+            touched_pools,
+        }
+    }
+}
+
+/// Run a swap against a sandbox snapshot and build the snapshot it leaves
+/// behind, with prices of the touched pools recomputed. Meant to run on a
+/// blocking worker.
+pub fn swap_in_sandbox(
+    snapshot: &BlockSnapshot,
+    route: &[ResolvedHop],
+    amount_in: BigUint,
+    limit_mode: LimitMode,
+    cancel: &CancelToken,
+) -> Result<(RouteOutcome, BlockSnapshot), ApiError> {
+    let outcome = simulate_path(snapshot, route, amount_in, limit_mode, cancel)?;
+
+    let mut next = snapshot.clone();
+    next.states
+        .extend(outcome.new_states.iter().map(|(id, state)| (id.clone(), state.clone())));
+    let touched: Vec<String> = outcome.new_states.keys().cloned().collect();
+    let prices = compute_batch(&next, &touched);
+    for (id, _) in prices.failures {
+        next.spot_prices.remove(&id);
+        next.pair_prices.remove(&id);
+    }
+    next.spot_prices.extend(prices.spot_prices);
+    next.pair_prices.extend(prices.pair_prices);
+    Ok((outcome, next))
+}
+
+struct SandboxEntry {
+    last_used: Instant,
+    sandbox: Arc<tokio::sync::Mutex<Sandbox>>,
+}
+
+/// Open sandboxes by id. Each sandbox sits behind its own async lock so swaps
+/// on it apply one after another.
+#[derive(Clone)]
+pub struct SandboxStore {
+    config: SandboxConfig,
+    sandboxes: Arc<Mutex<HashMap<String, SandboxEntry>>>,
+    // Seeds hard to guess sandbox ids
+    ids: RandomState,
+}
+
+impl SandboxStore {
+    pub fn new(config: SandboxConfig) -> Self {
+        SandboxStore {
+            config,
+            sandboxes: Arc::new(Mutex::new(HashMap::new())),
+            ids: RandomState::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SandboxConfig {
+        &self.config
+    }
+
+    /// Fork a new sandbox from a snapshot
+    pub fn create(&self, snapshot: Arc<BlockSnapshot>) -> Result<String, ApiError> {
+        let mut sandboxes = self.sandboxes.lock().unwrap();
+        self.prune(&mut sandboxes);
+        if sandboxes.len() >= self.config.max_sandboxes {
+            return Err(ApiError::Overloaded(format!(
+                "Too many open sessions (limit {})",
+                self.config.max_sandboxes
+            )));
+        }
+
+        let mut hasher = self.ids.build_hasher();
+        hasher.write_usize(sandboxes.len());
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        let id = format!("{:016x}", hasher.finish());
+
+        info!("Opening simulation session {} at block {}", id, snapshot.block_number);
+        let sandbox = Sandbox {
+            id: id.clone(),
+            created_at: SystemTime::now(),
+            forked_from_block: snapshot.block_number,
+            snapshot,
+            swaps: Vec::new(),
+            overridden_pools: Vec::new(),
+        };
+        sandboxes.insert(
+            id.clone(),
+            SandboxEntry {
+                last_used: Instant::now(),
+                sandbox: Arc::new(tokio::sync::Mutex::new(sandbox)),
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<tokio::sync::Mutex<Sandbox>>, ApiError> {
+        let mut sandboxes = self.sandboxes.lock().unwrap();
+        self.prune(&mut sandboxes);
+        let entry = sandboxes
+            .get_mut(id)
+            .ok_or_else(|| ApiError::NotFound(format!("Session not found: {}", id)))?;
+        entry.last_used = Instant::now();
+        Ok(entry.sandbox.clone())
+    }
+
+    /// Drop a sandbox, returns whether it existed
+    pub fn discard(&self, id: &str) -> bool {
+        self.sandboxes.lock().unwrap().remove(id).is_some()
+    }
+
+    fn prune(&self, sandboxes: &mut HashMap<String, SandboxEntry>) {
+        let idle_timeout = self.config.idle_timeout;
+        sandboxes.retain(|id, entry| {
+            let keep = entry.last_used.elapsed() < idle_timeout;
+            if !keep {
+                info!("Discarding idle simulation session {}", id);
+            }
+            keep
+        });
+    }
+}
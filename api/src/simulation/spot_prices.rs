@@ -0,0 +1,229 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tycho_simulation::tycho_core::{
+    models::token::Token, simulation::protocol_sim::ProtocolSim,
+};
+
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+
+/// Number of slowest pools reported in the metrics
+const SLOWEST_POOLS: usize = 5;
+
+/// Spot price of `base` quoted in `quote` for one token pair of a pool
+#[derive(Debug, Clone, Serialize)]
+pub struct PairPrice {
+    pub base: String,
+    pub quote: String,
+    pub price: f64,
+}
+
+/// Spot price of a pool in both directions, oriented towards a client's
+/// preferred quote tokens
+#[derive(Debug, Clone, Serialize)]
+pub struct OrientedPrice {
+    pub base: String,
+    pub quote: String,
+    pub price: f64,
+    /// Price of `quote` in `base`, missing when `price` is zero
+    pub inverse_price: Option<f64>,
+    /// Both prices as plain decimal strings, never in exponent notation.
+    /// Rendered from the f64 values, so no more precise than them.
+    pub price_str: String,
+    pub inverse_price_str: Option<String>,
+}
+
+impl OrientedPrice {
+    fn new(base: &str, quote: &str, price: f64) -> Self {
+        let inverse_price = (price != 0.0).then(|| 1.0 / price);
+        OrientedPrice {
+            base: base.to_string(),
+            quote: quote.to_string(),
+            price,
+            inverse_price,
+            price_str: price.to_string(),
+            inverse_price_str: inverse_price.map(|inverse| inverse.to_string()),
+        }
+    }
+
+    /// Quote the pool's price in the first of `quote_tokens` it trades,
+    /// falling back to the first pair's own orientation
+    pub fn orient(pairs: &[PairPrice], quote_tokens: &[String]) -> Option<Self> {
+        for token in quote_tokens {
+            for pair in pairs {
+                if pair.quote.eq_ignore_ascii_case(token) {
+                    return Some(Self::new(&pair.base, &pair.quote, pair.price));
+                }
+                if pair.base.eq_ignore_ascii_case(token) && pair.price != 0.0 {
+                    return Some(Self::new(&pair.quote, &pair.base, 1.0 / pair.price));
+                }
+            }
+        }
+        pairs
+            .first()
+            .map(|pair| Self::new(&pair.base, &pair.quote, pair.price))
+    }
+}
+
+/// Spot prices for every token pair of a pool, in token order. The first
+/// entry is always `tokens[0]` quoted in `tokens[1]`.
+fn pool_pair_prices(state: &dyn ProtocolSim, tokens: &[Token]) -> Result<Vec<PairPrice>, String> {
+    if tokens.len() < 2 {
+        return Err(format!("pool has {} tokens", tokens.len()));
+    }
+    let mut prices = Vec::new();
+    for (i, base) in tokens.iter().enumerate() {
+        for quote in &tokens[i + 1..] {
+            let price = state.spot_price(base, quote).map_err(|e| e.to_string())?;
+            if !price.is_finite() {
+                return Err(format!(
+                    "non-finite spot price {} for {}/{}",
+                    price, base.symbol, quote.symbol
+                ));
+            }
+            prices.push(PairPrice {
+                base: base.address.to_string(),
+                quote: quote.address.to_string(),
+                price,
+            });
+        }
+    }
+    Ok(prices)
+}
+
+/// Spot prices computed by one batch plus how long each pool took
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub spot_prices: HashMap<String, f64>,
+    pub pair_prices: HashMap<String, Vec<PairPrice>>,
+    pub timings: Vec<(String, Duration)>,
+    /// Pools whose spot price errored or panicked, with the reason
+    pub failures: Vec<(String, String)>,
+}
+
+/// Compute spot prices for the given pools against a snapshot. Batches only
+/// read the snapshot, so any number of them can run side by side. A pool that
+/// fails is reported in `failures` and doesn't affect the rest of the batch.
+pub fn compute_batch(snapshot: &BlockSnapshot, ids: &[String]) -> BatchResult {
+    let mut result = BatchResult::default();
+    for addr in ids {
+        if let (Some(component), Some(state)) = snapshot.get_pool_state(addr) {
+            let started = Instant::now();
+            let prices = isolate(|| pool_pair_prices(state, &component.tokens));
+            result.timings.push((addr.clone(), started.elapsed()));
+            match prices {
+                Ok(prices) => {
+                    result.spot_prices.insert(addr.clone(), prices[0].price);
+                    result.pair_prices.insert(addr.clone(), prices);
+                }
+                Err(reason) => result.failures.push((addr.clone(), reason)),
+            }
+        }
+    }
+    result
+}
+
+/// Split pools into `parallelism` batches round-robin, so expensive pools of
+/// the same protocol (which tend to be adjacent) end up spread across batches
+pub fn split_batches(ids: Vec<String>, parallelism: usize) -> Vec<Vec<String>> {
+    let parallelism = parallelism.max(1).min(ids.len().max(1));
+    let mut batches = vec![Vec::new(); parallelism];
+    for (i, id) in ids.into_iter().enumerate() {
+        batches[i % parallelism].push(id);
+    }
+    batches
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolTiming {
+    pub pool: String,
+    pub micros: u64,
+}
+
+/// Timings of the spot price computation for the latest block
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SpotPriceMetrics {
+    pub block_number: u64,
+    pub pools: usize,
+    pub batches: usize,
+    /// Wall-clock time for the whole block
+    pub wall_micros: u64,
+    /// Sum of the time spent on every pool
+    pub total_pool_micros: u64,
+    pub slowest_pools: Vec<PoolTiming>,
+}
+
+impl SpotPriceMetrics {
+    pub fn new(
+        block_number: u64,
+        batches: usize,
+        wall_time: Duration,
+        mut timings: Vec<(String, Duration)>,
+    ) -> Self {
+        let total_pool_micros = timings.iter().map(|(_, t)| t.as_micros() as u64).sum();
+        let pools = timings.len();
+        timings.sort_by(|a, b| b.1.cmp(&a.1));
+        let slowest_pools = timings
+            .into_iter()
+            .take(SLOWEST_POOLS)
+            .map(|(pool, t)| PoolTiming {
+                pool,
+                micros: t.as_micros() as u64,
+            })
+            .collect();
+        SpotPriceMetrics {
+            block_number,
+            pools,
+            batches,
+            wall_micros: wall_time.as_micros() as u64,
+            total_pool_micros,
+            slowest_pools,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("pool{}", i)).collect()
+    }
+
+    #[test]
+    fn split_batches_spreads_pools_round_robin() {
+        let batches = split_batches(ids(5), 2);
+        assert_eq!(
+            batches,
+            vec![vec!["pool0", "pool2", "pool4"], vec!["pool1", "pool3"]]
+        );
+    }
+
+    #[test]
+    fn split_batches_never_returns_empty_batches_for_few_pools() {
+        let batches = split_batches(ids(2), 8);
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.len() == 1));
+    }
+
+    #[test]
+    fn split_batches_keeps_every_pool() {
+        let batches = split_batches(ids(100), 7);
+        assert_eq!(batches.len(), 7);
+        let mut pools: Vec<String> = batches.into_iter().flatten().collect();
+        pools.sort();
+        let mut expected = ids(100);
+        expected.sort();
+        assert_eq!(pools, expected);
+    }
+
+    #[test]
+    fn split_batches_handles_zero_parallelism_and_no_pools() {
+        assert_eq!(split_batches(ids(3), 0), vec![ids(3)]);
+        assert_eq!(split_batches(Vec::new(), 4), vec![Vec::<String>::new()]);
+    }
+}
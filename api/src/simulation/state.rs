@@ -1,28 +1,345 @@
+use futures::future::join_all;
 use serde::Serialize;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{broadcast, RwLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
 use tycho_simulation::{
     protocol::models::{Update as BlockUpdate, ProtocolComponent},
-    tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim},
+    tycho_client::feed::synchronizer::SynchronizerState,
+    tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim, Bytes},
 };
 
+use super::executor::SimulationExecutor;
+use super::fees::fee_bps;
+use super::outbox::{Subscribers, Subscription};
+use super::pricing::{numeraire_prices, PricingConfig};
+use super::quarantine::QuarantineEntry;
+use super::swap::find_token;
+use super::spot_prices::{compute_batch, split_batches, OrientedPrice, PairPrice, SpotPriceMetrics};
+
+/// Number of past block snapshots kept around to roll back to on a reorg
+pub const DEFAULT_HISTORY_DEPTH: usize = 64;
+
+/// Per-pool map of a snapshot. Persistent, so a clone shares every entry with
+/// the original and only the entries a block changes are copied.
+pub type PoolMap<V> = imbl::HashMap<String, V>;
+
+/// Immutable view of every pool as of a single block.
+///
+/// A new snapshot is built for each block update and swapped in atomically, so
+/// anything holding one reads pool states, components and prices that all
+/// belong to the same block. Consecutive snapshots, including the ones kept
+/// for reorgs, share the pools they have in common.
+#[derive(Debug, Clone, Default)]
+pub struct BlockSnapshot {
+    pub block_number: u64,
+    // Hash of the block, when the stream reported one
+    pub block_hash: Option<Bytes>,
+    pub states: PoolMap<Arc<dyn ProtocolSim>>,
+    pub components: PoolMap<Arc<ProtocolComponent>>,
+    // Spot price of every pool, as pushed to clients
+    pub spot_prices: PoolMap<f64>,
+    // Spot prices for every token pair of every pool
+    pub pair_prices: PoolMap<Vec<PairPrice>>,
+    // Fee each pool currently charges, in basis points
+    pub fees: PoolMap<f64>,
+    // Pools whose simulation is failing, excluded from prices and quotes
+    pub quarantined: PoolMap<QuarantineEntry>,
+    // Price of each token in the numeraire, derived from the pair prices
+    pub token_prices: Arc<HashMap<String, f64>>,
+    // What changed in this block
+    pub summary: BlockSummary,
+}
+
+impl BlockSnapshot {
+    /// Method to get pool state for simulation
+    pub fn get_pool_state(
+        &self,
+        address: &str,
+    ) -> (Option<&ProtocolComponent>, Option<&dyn ProtocolSim>) {
+        let pool_state = self.states.get(address).map(|state| state.as_ref());
+        let component = self.components.get(address).map(|c| c.as_ref());
+        (component, pool_state)
+    }
+
+    /// Any pool's copy of a token, to look up its decimals
+    pub fn find_token(&self, address: &str) -> Option<&Token> {
+        self.components
+            .values()
+            .find_map(|component| find_token(component, address))
+    }
+
+    /// Take a pool whose simulation failed out of prices and quotes, or count
+    /// another failure if it already is
+    pub fn quarantine(&mut self, pool: &str, reason: String, block_number: u64) {
+        self.spot_prices.remove(pool);
+        self.pair_prices.remove(pool);
+        match self.quarantined.get_mut(pool) {
+            Some(entry) => entry.failed_again(reason, block_number),
+            None => {
+                let protocol_system = self
+                    .components
+                    .get(pool)
+                    .map(|c| c.protocol_system.clone())
+                    .unwrap_or_default();
+                let entry = QuarantineEntry::new(pool, &protocol_system, reason, block_number);
+                self.quarantined.insert(pool.to_string(), entry);
+            }
+        }
+    }
+
+    /// Put a quarantined pool back into service, returning its entry
+    pub fn release(&mut self, pool: &str) -> Option<QuarantineEntry> {
+        self.quarantined.remove(pool)
+    }
+
+    /// Pair prices of the given pools that have more than two tokens. For the
+    /// others `spot_prices` already covers their only pair.
+    pub fn multi_token_prices<'a>(
+        &self,
+        ids: impl IntoIterator<Item = &'a String>,
+    ) -> HashMap<String, Vec<PairPrice>> {
+        ids.into_iter()
+            .filter_map(|id| {
+                let prices = self.pair_prices.get(id)?;
+                (prices.len() > 1).then(|| (id.clone(), prices.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Where a block sits in the chain, from the header the stream sent with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockLink {
+    number: u64,
+    hash: Bytes,
+    parent_hash: Bytes,
+    /// Set when the stream rolls the state back to this block
+    revert: bool,
+}
+
+impl BlockLink {
+    /// Header of the block an update belongs to. Every ready protocol
+    /// synchronizer reports the same block, so any of them will do.
+    fn of(update: &BlockUpdate) -> Option<Self> {
+        update.sync_states.values().find_map(|state| match state {
+            SynchronizerState::Ready(header) => Some(BlockLink {
+                number: header.number,
+                hash: header.hash.clone(),
+                parent_hash: header.parent_hash.clone(),
+                revert: header.revert,
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Where a new block attaches to the blocks already applied
+#[derive(Debug, PartialEq, Eq)]
+enum Attach {
+    /// On top of the current head
+    Head,
+    /// On top of the remembered snapshot at this position of the history
+    RollBack(usize),
+    /// On a block that was never seen or has left the history
+    Unknown,
+}
+
+fn attach_point(
+    history: &VecDeque<Arc<BlockSnapshot>>,
+    head: &BlockSnapshot,
+    link: &BlockLink,
+) -> Attach {
+    // Nothing to compare against before the first block with a header
+    let Some(head_hash) = &head.block_hash else {
+        return Attach::Head;
+    };
+    // A block that was already applied, such as the fork point of a revert or
+    // a re-delivered block, goes back to its own snapshot. Anything else
+    // builds on its parent.
+    for base in [&link.hash, &link.parent_hash] {
+        if head_hash == base {
+            return Attach::Head;
+        }
+        if let Some(pos) = history
+            .iter()
+            .rposition(|snapshot| snapshot.block_hash.as_ref() == Some(base))
+        {
+            return Attach::RollBack(pos);
+        }
+    }
+    Attach::Unknown
+}
+
 /// Represents the current state of the simulation
 #[derive(Debug, Clone)]
 pub struct SimulationState {
-    states: Arc<RwLock<HashMap<String, Box<dyn ProtocolSim>>>>,
-    components: Arc<RwLock<HashMap<String, ProtocolComponent>>>,
-    current_block: u64,
-    // A broadcast channel to notify listeners of new updates
-    updates: broadcast::Sender<ClientUpdate>,
+    // Snapshot of the latest block, replaced wholesale on every update
+    snapshot: Arc<RwLock<Arc<BlockSnapshot>>>,
+    // Recent snapshots, oldest first, for reorg handling
+    history: Arc<Mutex<VecDeque<Arc<BlockSnapshot>>>>,
+    history_depth: usize,
+    // Bounded worker pool for CPU-heavy simulation work
+    executor: SimulationExecutor,
+    // Gas price and reference tokens used to value gas and tokens
+    pricing: PricingConfig,
+    // Timings of the latest spot price computation
+    spot_price_metrics: Arc<RwLock<SpotPriceMetrics>>,
+    // Per-client outboxes to notify listeners of new updates
+    subscribers: Subscribers,
 }
 
 // Define your custom update struct
-#[derive(Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Default)]
 pub struct ClientUpdate {
     pub block_number: u64,
     pub new_pairs: HashMap<String, ProtocolComponent>,
+    pub removed_pairs: HashMap<String, ProtocolComponent>,
     pub spot_prices: HashMap<String, f64>,
+    /// Prices of every token pair for updated pools with more than two tokens
+    pub pair_prices: HashMap<String, Vec<PairPrice>>,
+    /// Fees in basis points of new pools and pools whose fee changed
+    pub fees: HashMap<String, f64>,
     pub tvl_updates: HashMap<String, f64>,
+    /// Pools whose state changed in this block, with their price move
+    pub updated_pools: HashMap<String, PriceChange>,
+    /// Set when this message undoes previously sent blocks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert: Option<RevertInfo>,
+}
+
+/// Blocks rolled back by a chain reorg
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct RevertInfo {
+    /// Last block clients had seen before the revert
+    pub reverted_from: u64,
+    /// Block the state was rolled back to
+    pub reverted_to: u64,
+}
+
+/// Spot price move of a single pool within a block
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct PriceChange {
+    pub previous_spot_price: Option<f64>,
+    pub spot_price: f64,
+    pub change_pct: Option<f64>,
+}
+
+impl PriceChange {
+    pub fn new(previous_spot_price: Option<f64>, spot_price: f64) -> Self {
+        let change_pct = previous_spot_price
+            .filter(|previous| *previous != 0.0)
+            .map(|previous| (spot_price - previous) / previous * 100.0);
+        PriceChange {
+            previous_spot_price,
+            spot_price,
+            change_pct,
+        }
+    }
+}
+
+/// What changed in the latest block, served by `GET /api/blocks/latest`
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct BlockSummary {
+    pub block_number: u64,
+    pub updated_pools: HashMap<String, PriceChange>,
+    pub new_pairs: Vec<String>,
+    pub removed_pairs: Vec<String>,
+}
+
+impl From<&ClientUpdate> for BlockSummary {
+    fn from(update: &ClientUpdate) -> Self {
+        BlockSummary {
+            block_number: update.block_number,
+            updated_pools: update.updated_pools.clone(),
+            new_pairs: update.new_pairs.keys().cloned().collect(),
+            removed_pairs: update.removed_pairs.keys().cloned().collect(),
+        }
+    }
+}
+
+impl ClientUpdate {
+    /// Spot prices of the update quoted in the first of `quote_tokens` each
+    /// pool trades. Tokens of pools the update doesn't add come from `snapshot`.
+    pub fn oriented_prices(
+        &self,
+        snapshot: &BlockSnapshot,
+        quote_tokens: &[String],
+    ) -> HashMap<String, OrientedPrice> {
+        self.spot_prices
+            .iter()
+            .filter_map(|(id, price)| {
+                let pairs = match self.pair_prices.get(id) {
+                    Some(pairs) => pairs.clone(),
+                    None => {
+                        let tokens = match self.new_pairs.get(id) {
+                            Some(component) => &component.tokens,
+                            None => &snapshot.components.get(id)?.tokens,
+                        };
+                        vec![PairPrice {
+                            base: tokens.first()?.address.to_string(),
+                            quote: tokens.get(1)?.address.to_string(),
+                            price: *price,
+                        }]
+                    }
+                };
+                Some((id.clone(), OrientedPrice::orient(&pairs, quote_tokens)?))
+            })
+            .collect()
+    }
+
+    /// Fold a later update into this one so that applying the result is
+    /// equivalent to applying both in order
+    pub fn merge(&mut self, later: &ClientUpdate) {
+        self.block_number = later.block_number;
+
+        for (id, component) in &later.removed_pairs {
+            self.new_pairs.remove(id);
+            self.spot_prices.remove(id);
+            self.pair_prices.remove(id);
+            self.fees.remove(id);
+            self.tvl_updates.remove(id);
+            self.updated_pools.remove(id);
+            self.removed_pairs.insert(id.clone(), component.clone());
+        }
+        for (id, component) in &later.new_pairs {
+            self.removed_pairs.remove(id);
+            self.new_pairs.insert(id.clone(), component.clone());
+        }
+
+        // Latest value per pool wins
+        self.spot_prices
+            .extend(later.spot_prices.iter().map(|(k, v)| (k.clone(), *v)));
+        self.pair_prices
+            .extend(later.pair_prices.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.fees
+            .extend(later.fees.iter().map(|(k, v)| (k.clone(), *v)));
+        self.tvl_updates
+            .extend(later.tvl_updates.iter().map(|(k, v)| (k.clone(), *v)));
+
+        // Keep the earliest previous price so the change spans the whole merged range
+        for (id, change) in &later.updated_pools {
+            let previous = match self.updated_pools.get(id) {
+                Some(earlier) => earlier.previous_spot_price,
+                None => change.previous_spot_price,
+            };
+            self.updated_pools
+                .insert(id.clone(), PriceChange::new(previous, change.spot_price));
+        }
+
+        if let Some(later_revert) = later.revert {
+            self.revert = Some(match self.revert {
+                Some(earlier) => RevertInfo {
+                    reverted_from: earlier.reverted_from.max(later_revert.reverted_from),
+                    reverted_to: earlier.reverted_to.min(later_revert.reverted_to),
+                },
+                None => later_revert,
+            });
+        }
+    }
 }
 
 impl From<BlockUpdate> for ClientUpdate {
@@ -37,95 +354,578 @@ impl From<BlockUpdate> for ClientUpdate {
         ClientUpdate {
             block_number: update.block_number_or_timestamp,
             new_pairs: update.new_pairs,
+            removed_pairs: update.removed_pairs,
             spot_prices,
             tvl_updates,
+            ..Default::default()
         }
     }
 }
 
 impl SimulationState {
-    pub fn new() -> Self {
-        // Create a channel with a maximum buffer size of 100 messages
-        let (tx, _) = broadcast::channel(100);
-
+    pub fn new(history_depth: usize, executor: SimulationExecutor, pricing: PricingConfig) -> Self {
         SimulationState {
-            states: Arc::new(RwLock::new(HashMap::new())),
-            components: Arc::new(RwLock::new(HashMap::new())),
-            current_block: 0,
-            updates: tx,
+            snapshot: Arc::new(RwLock::new(Arc::new(BlockSnapshot::default()))),
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(history_depth))),
+            history_depth,
+            executor,
+            pricing,
+            spot_price_metrics: Arc::new(RwLock::new(SpotPriceMetrics::default())),
+            subscribers: Subscribers::default(),
         }
     }
 
+    /// The latest block snapshot. Hold on to it for the duration of a request
+    /// to get a consistent view.
+    pub fn snapshot(&self) -> Arc<BlockSnapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    pub fn executor(&self) -> &SimulationExecutor {
+        &self.executor
+    }
+
+    pub fn pricing(&self) -> &PricingConfig {
+        &self.pricing
+    }
+
+    fn swap_snapshot(&self, snapshot: Arc<BlockSnapshot>) {
+        *self.snapshot.write().unwrap() = snapshot;
+    }
+
     /// Update the state with a new block update
     pub async fn update(&self, update: BlockUpdate) {
-        // Add the update to our storage
-        {
-            self.states.write().await.extend(update.states.clone());
-            self.components
-                .write()
-                .await
-                .extend(update.new_pairs.clone());
+        let block_number = update.block_number_or_timestamp;
+
+        // A block that doesn't build on the current head means the chain
+        // reorged, so roll back to the block it does build on
+        let link = BlockLink::of(&update);
+        let mut base = self.snapshot();
+        if let Some(link) = &link {
+            base = self.revert(link, base);
         }
 
+        // Build the next snapshot on top of the previous one, sharing the pools
+        // this block leaves alone
+        let mut next = BlockSnapshot {
+            block_number,
+            block_hash: link.map(|link| link.hash),
+            summary: BlockSummary::default(),
+            ..base.as_ref().clone()
+        };
+        for id in update.removed_pairs.keys() {
+            next.states.remove(id);
+            next.components.remove(id);
+            next.spot_prices.remove(id);
+            next.pair_prices.remove(id);
+            next.fees.remove(id);
+            next.quarantined.remove(id);
+        }
+        for (id, component) in &update.new_pairs {
+            next.components
+                .insert(id.clone(), Arc::new(component.clone()));
+        }
+        for (id, state) in &update.states {
+            next.states.insert(id.clone(), Arc::from(state.clone()));
+        }
+
+        // Spot prices can be expensive for VM pools, so compute them in
+        // parallel batches on the worker pool, each reading the shared snapshot
+        let started = Instant::now();
+        let next = Arc::new(next);
+        let updated_ids: Vec<String> = update.states.keys().cloned().collect();
+        let batches = split_batches(updated_ids, self.executor.ingest_concurrency());
+        let batch_count = batches.len();
+        let jobs = batches.into_iter().map(|ids| {
+            let snapshot = next.clone();
+            let executor = self.executor.clone();
+            async move {
+                let computed = executor
+                    .run_ingest({
+                        let ids = ids.clone();
+                        move || compute_batch(&snapshot, &ids)
+                    })
+                    .await;
+                (ids, computed)
+            }
+        });
+
         let mut spot_prices = HashMap::new();
-        for (addr, state) in update.states.clone() {
-            if let Some(tokens) = self.get_tokens(&addr).await {
-                let spot_price = state.spot_price(&tokens[0], &tokens[1]).unwrap();
-                spot_prices.insert(addr.clone(), spot_price);
+        let mut pair_prices = HashMap::new();
+        let mut timings = Vec::new();
+        let mut failures = Vec::new();
+        for (ids, computed) in join_all(jobs).await {
+            match computed {
+                Ok(batch) => {
+                    spot_prices.extend(batch.spot_prices);
+                    pair_prices.extend(batch.pair_prices);
+                    timings.extend(batch.timings);
+                    failures.extend(batch.failures);
+                }
+                // Still apply the block, quarantining the pools whose prices are unknown
+                Err(e) => {
+                    error!(
+                        "Spot price batch of {} pools failed in block {}: {}",
+                        ids.len(),
+                        block_number,
+                        e
+                    );
+                    let reason = e.to_string();
+                    failures.extend(ids.into_iter().map(|id| (id, reason.clone())));
+                }
+            }
+        }
+        let metrics = SpotPriceMetrics::new(block_number, batch_count, started.elapsed(), timings);
+        debug!(
+            "Computed {} spot prices for block {} in {}us",
+            metrics.pools, block_number, metrics.wall_micros
+        );
+        *self.spot_price_metrics.write().unwrap() = metrics;
+
+        // Every batch has finished, so this is normally the only reference left
+        let mut next = Arc::try_unwrap(next).unwrap_or_else(|shared| shared.as_ref().clone());
+
+        // Pools that priced fine again are released, failing ones are quarantined
+        for addr in spot_prices.keys() {
+            if let Some(entry) = next.release(addr) {
+                info!("Releasing pool {} from quarantine after {} failures", addr, entry.failures);
             }
         }
-        
+        for (addr, reason) in failures {
+            warn!("Quarantining pool {} at block {}: {}", addr, block_number, reason);
+            next.quarantine(&addr, reason, block_number);
+        }
+
+        // Fees rarely move, so clients only hear about new values
+        let mut fees = HashMap::new();
+        for id in update.states.keys() {
+            let (Some(component), Some(state)) = (next.components.get(id), next.states.get(id)) else {
+                continue;
+            };
+            let Some(fee) = fee_bps(state.as_ref(), component) else {
+                continue;
+            };
+            if next.fees.insert(id.clone(), fee) != Some(fee) {
+                fees.insert(id.clone(), fee);
+            }
+        }
+
+        // Compare against the last known prices to describe what moved
+        let mut updated_pools = HashMap::new();
+        for (addr, spot_price) in &spot_prices {
+            let previous = next.spot_prices.insert(addr.clone(), *spot_price);
+            updated_pools.insert(addr.clone(), PriceChange::new(previous, *spot_price));
+        }
+        next.pair_prices.extend(pair_prices);
+        next.token_prices = Arc::new(numeraire_prices(&next, &self.pricing.numeraire));
+
         // Create the update message with the calculated spot prices
         let mut update_msg = ClientUpdate::from(update);
+        update_msg.pair_prices = next.multi_token_prices(spot_prices.keys());
         update_msg.spot_prices = spot_prices;
+        update_msg.fees = fees;
+        update_msg.updated_pools = updated_pools;
+        next.summary = BlockSummary::from(&update_msg);
 
-        // Broadcast the update to all subscribers
-        let _ = self.updates.send(update_msg);
-    }
+        let next = Arc::new(next);
+        self.swap_snapshot(next.clone());
+        self.remember(next);
 
-    pub async fn get_tokens(&self, address: &str) -> Option<Vec<Token>> {
-        self.components
-            .read()
-            .await
-            .get(address)
-            .map(|c| c.tokens.clone())
+        // Hand the update to every subscriber's outbox
+        self.subscribers.publish(update_msg);
     }
 
-    /// Method to get pool state for simulation
-    pub async fn get_pool_state(
-        &self,
-        address: &str,
-    ) -> (Option<ProtocolComponent>, Option<Box<dyn ProtocolSim>>) {
-        let pool_state = self.states.read().await.get(address).cloned();
-        let component = self.components.read().await.get(address).cloned();
-        (component, pool_state)
+    fn remember(&self, snapshot: Arc<BlockSnapshot>) {
+        if self.history_depth == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        // A re-delivered block replaces the copy it was rebuilt from
+        if history
+            .back()
+            .is_some_and(|last| last.block_hash.is_some() && last.block_hash == snapshot.block_hash)
+        {
+            history.pop_back();
+        }
+        if history.len() == self.history_depth {
+            history.pop_front();
+        }
+        history.push_back(snapshot);
     }
 
-    pub async fn get_full_state(&self) -> ClientUpdate {
-        let all_states = self.states.read().await.clone();
+    /// Roll back to the snapshot of the block `link` builds on and send
+    /// clients a correcting update for everything that differs. Returns the
+    /// snapshot to build on, which is `current` if the block extends it or
+    /// history doesn't reach back far enough.
+    fn revert(&self, link: &BlockLink, current: Arc<BlockSnapshot>) -> Arc<BlockSnapshot> {
+        let restored = {
+            let mut history = self.history.lock().unwrap();
+            match attach_point(&history, &current, link) {
+                Attach::Head => return current,
+                Attach::RollBack(pos) => {
+                    history.truncate(pos + 1);
+                    history[pos].clone()
+                }
+                Attach::Unknown => {
+                    if link.revert || link.number <= current.block_number {
+                        warn!(
+                            "Reorg to block {} is deeper than the history, keeping state of block {}",
+                            link.number, current.block_number
+                        );
+                    } else {
+                        // Blocks were missed, e.g. while the stream reconnected
+                        debug!(
+                            "Block {} doesn't build on block {}, applying it on top",
+                            link.number, current.block_number
+                        );
+                    }
+                    return current;
+                }
+            }
+        };
+        info!(
+            "Reverting blocks: {} -> {}",
+            current.block_number, restored.block_number
+        );
+
+        let removed_pairs = current
+            .components
+            .iter()
+            .filter(|(id, _)| !restored.components.contains_key(*id))
+            .map(|(id, component)| (id.clone(), component.as_ref().clone()))
+            .collect();
+        let new_pairs = restored
+            .components
+            .iter()
+            .filter(|(id, _)| !current.components.contains_key(*id))
+            .map(|(id, component)| (id.clone(), component.as_ref().clone()))
+            .collect();
+
         let mut spot_prices = HashMap::new();
-        for (addr, state) in all_states {
-            if let Some(tokens) = self.get_tokens(&addr).await {
-                let spot_price = state.spot_price(&tokens[0], &tokens[1]).unwrap();
-                spot_prices.insert(addr.clone(), spot_price);
+        let mut updated_pools = HashMap::new();
+        for (id, price) in &restored.spot_prices {
+            let before = current.spot_prices.get(id).copied();
+            if before != Some(*price) {
+                spot_prices.insert(id.clone(), *price);
+                updated_pools.insert(id.clone(), PriceChange::new(before, *price));
             }
         }
-        return ClientUpdate {
-            block_number: self.current_block,
-            new_pairs: self.components.read().await.clone(),
+
+        let fees = restored
+            .fees
+            .iter()
+            .filter(|(id, fee)| current.fees.get(*id) != Some(*fee))
+            .map(|(id, fee)| (id.clone(), *fee))
+            .collect();
+
+        let revert_msg = ClientUpdate {
+            block_number: restored.block_number,
+            new_pairs,
+            removed_pairs,
+            pair_prices: restored.multi_token_prices(spot_prices.keys()),
             spot_prices,
-            tvl_updates: HashMap::new(),
+            fees,
+            updated_pools,
+            revert: Some(RevertInfo {
+                reverted_from: current.block_number,
+                reverted_to: restored.block_number,
+            }),
+            ..Default::default()
         };
+        self.swap_snapshot(restored.clone());
+        self.subscribers.publish(revert_msg);
+        restored
+    }
+
+    pub fn get_full_state(&self) -> ClientUpdate {
+        let snapshot = self.snapshot();
+        ClientUpdate {
+            block_number: snapshot.block_number,
+            new_pairs: snapshot
+                .components
+                .iter()
+                .map(|(id, component)| (id.clone(), component.as_ref().clone()))
+                .collect(),
+            spot_prices: snapshot.spot_prices.iter().map(|(id, price)| (id.clone(), *price)).collect(),
+            pair_prices: snapshot.multi_token_prices(snapshot.pair_prices.keys()),
+            fees: snapshot.fees.iter().map(|(id, fee)| (id.clone(), *fee)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Summary of the most recently applied block
+    pub fn get_latest_block(&self) -> BlockSummary {
+        self.snapshot().summary.clone()
+    }
+
+    /// Timings of the spot price computation for the latest block
+    pub fn spot_price_metrics(&self) -> SpotPriceMetrics {
+        self.spot_price_metrics.read().unwrap().clone()
+    }
+
+    /// Latest block applied to the state
+    pub fn current_block(&self) -> u64 {
+        self.snapshot().block_number
     }
 
     /// Subscribe to receive all future block updates
-    pub fn subscribe_to_updates(&self) -> broadcast::Receiver<ClientUpdate> {
-        self.updates.subscribe()
+    pub fn subscribe_to_updates(&self) -> Subscription {
+        self.subscribers.subscribe()
     }
 }
 
-impl Default for SimulationState {
-    fn default() -> Self {
-        Self::new()
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::executor::ExecutorConfig;
+    use futures::FutureExt;
+    use std::time::Duration;
+    use tycho_simulation::tycho_core::models::Chain;
+
+    fn component(id: &str) -> ProtocolComponent {
+        ProtocolComponent::new(
+            Bytes::from(id.as_bytes().to_vec()),
+            "uniswap_v2".to_string(),
+            "uniswap_v2_pool".to_string(),
+            Chain::Ethereum,
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            Bytes::default(),
+            Default::default(),
+        )
+    }
+
+    fn block(block_number: u64) -> ClientUpdate {
+        ClientUpdate {
+            block_number,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_drops_a_pool_added_then_removed() {
+        let mut merged = block(1);
+        merged.new_pairs.insert("pool".to_string(), component("pool"));
+        merged.spot_prices.insert("pool".to_string(), 1.0);
+        merged.fees.insert("pool".to_string(), 30.0);
+
+        let mut later = block(2);
+        later.removed_pairs.insert("pool".to_string(), component("pool"));
+        merged.merge(&later);
+
+        assert_eq!(merged.block_number, 2);
+        assert!(!merged.new_pairs.contains_key("pool"));
+        assert!(!merged.spot_prices.contains_key("pool"));
+        assert!(!merged.fees.contains_key("pool"));
+        assert!(merged.removed_pairs.contains_key("pool"));
+    }
+
+    #[test]
+    fn merge_keeps_a_pool_removed_then_re_added() {
+        let mut merged = block(1);
+        merged.removed_pairs.insert("pool".to_string(), component("pool"));
+
+        let mut later = block(2);
+        later.new_pairs.insert("pool".to_string(), component("pool"));
+        later.spot_prices.insert("pool".to_string(), 2.0);
+        merged.merge(&later);
+
+        assert!(!merged.removed_pairs.contains_key("pool"));
+        assert!(merged.new_pairs.contains_key("pool"));
+        assert_eq!(merged.spot_prices.get("pool"), Some(&2.0));
+    }
+
+    #[test]
+    fn merge_keeps_the_earliest_previous_price() {
+        let mut merged = block(1);
+        merged.spot_prices.insert("pool".to_string(), 110.0);
+        merged
+            .updated_pools
+            .insert("pool".to_string(), PriceChange::new(Some(100.0), 110.0));
+
+        let mut later = block(2);
+        later.spot_prices.insert("pool".to_string(), 120.0);
+        later
+            .updated_pools
+            .insert("pool".to_string(), PriceChange::new(Some(110.0), 120.0));
+        merged.merge(&later);
+
+        let change = merged.updated_pools["pool"];
+        assert_eq!(change.previous_spot_price, Some(100.0));
+        assert_eq!(change.spot_price, 120.0);
+        assert_eq!(merged.spot_prices.get("pool"), Some(&120.0));
+    }
+
+    #[test]
+    fn merge_chains_reverts_into_the_widest_range() {
+        let mut merged = block(8);
+        merged.revert = Some(RevertInfo {
+            reverted_from: 10,
+            reverted_to: 8,
+        });
+
+        // A block on top of the revert keeps it
+        merged.merge(&block(9));
+        let mut deeper = block(6);
+        deeper.revert = Some(RevertInfo {
+            reverted_from: 9,
+            reverted_to: 6,
+        });
+        merged.merge(&deeper);
+
+        let revert = merged.revert.unwrap();
+        assert_eq!(revert.reverted_from, 10);
+        assert_eq!(revert.reverted_to, 6);
+        assert_eq!(merged.block_number, 6);
+    }
+
+    fn state(history_depth: usize) -> SimulationState {
+        let executor = SimulationExecutor::new(ExecutorConfig {
+            max_concurrency: 1,
+            ingest_concurrency: 1,
+            queue_timeout: Duration::from_secs(1),
+            job_timeout: Duration::from_secs(1),
+        });
+        let pricing = PricingConfig {
+            gas_price_wei: None,
+            native_token: "weth".to_string(),
+            numeraire: "usdc".to_string(),
+        };
+        SimulationState::new(history_depth, executor, pricing)
+    }
+
+    fn hash(block: u64) -> Bytes {
+        Bytes::from(block.to_be_bytes().to_vec())
+    }
+
+    /// Hash of the block at `block` on a competing fork
+    fn fork_hash(block: u64) -> Bytes {
+        Bytes::from([block.to_be_bytes(), [1; 8]].concat())
+    }
+
+    fn link(number: u64, hash: Bytes, parent_hash: Bytes) -> BlockLink {
+        BlockLink {
+            number,
+            hash,
+            parent_hash,
+            revert: false,
+        }
+    }
+
+    /// Apply blocks `1..=blocks` as `update` would. Pool `a` moves every
+    /// block, pool `b` only in block 2.
+    fn apply_blocks(state: &SimulationState, blocks: u64) {
+        for block in 1..=blocks {
+            let snapshot = Arc::new(BlockSnapshot {
+                block_number: block,
+                block_hash: Some(hash(block)),
+                spot_prices: PoolMap::from_iter([
+                    ("a".to_string(), block as f64),
+                    ("b".to_string(), if block >= 2 { 20.0 } else { 10.0 }),
+                ]),
+                ..Default::default()
+            });
+            state.swap_snapshot(snapshot.clone());
+            state.remember(snapshot);
+        }
+    }
+
+    fn history_blocks(state: &SimulationState) -> Vec<u64> {
+        state
+            .history
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|snapshot| snapshot.block_number)
+            .collect()
+    }
+
+    #[test]
+    fn extends_the_head_without_a_revert() {
+        let state = state(8);
+        apply_blocks(&state, 3);
+        let subscription = state.subscribe_to_updates();
+
+        let base = state.revert(&link(4, hash(4), hash(3)), state.snapshot());
+        assert_eq!(base.block_number, 3);
+        assert_eq!(history_blocks(&state), vec![1, 2, 3]);
+        assert!(subscription.outbox().recv().now_or_never().is_none());
+    }
+
+    #[test]
+    fn rolls_back_to_the_parent_of_a_forked_block() {
+        let state = state(8);
+        apply_blocks(&state, 3);
+        let subscription = state.subscribe_to_updates();
+
+        let base = state.revert(&link(3, fork_hash(3), hash(2)), state.snapshot());
+        assert_eq!(base.block_number, 2);
+        assert_eq!(state.current_block(), 2);
+        assert_eq!(history_blocks(&state), vec![1, 2]);
+
+        let message = subscription.outbox().recv().now_or_never().unwrap();
+        let revert = message.revert.unwrap();
+        assert_eq!((revert.reverted_from, revert.reverted_to), (3, 2));
+        // Only what differs from block 3 is corrected
+        assert_eq!(message.spot_prices, HashMap::from([("a".to_string(), 2.0)]));
+    }
+
+    #[test]
+    fn rolls_back_to_a_re_delivered_fork_point() {
+        let state = state(8);
+        apply_blocks(&state, 3);
+
+        // The fork point comes again, possibly with an empty delta. Pools
+        // that changed in it must keep their values from it.
+        let base = state.revert(&link(2, hash(2), hash(1)), state.snapshot());
+        assert_eq!(base.block_number, 2);
+        assert_eq!(base.spot_prices.get("b"), Some(&20.0));
+        assert_eq!(history_blocks(&state), vec![1, 2]);
+
+        // Applying it again replaces the copy instead of adding another
+        let reapplied = Arc::new(base.as_ref().clone());
+        state.remember(reapplied);
+        assert_eq!(history_blocks(&state), vec![1, 2]);
+    }
+
+    #[test]
+    fn rolls_back_to_the_block_a_revert_names() {
+        let state = state(8);
+        apply_blocks(&state, 4);
+
+        let mut revert = link(2, hash(2), hash(1));
+        revert.revert = true;
+        let base = state.revert(&revert, state.snapshot());
+        assert_eq!(base.block_number, 2);
+        assert_eq!(history_blocks(&state), vec![1, 2]);
+    }
+
+    #[test]
+    fn keeps_the_state_when_a_reorg_is_deeper_than_the_history() {
+        let state = state(3);
+        apply_blocks(&state, 5);
+        assert_eq!(history_blocks(&state), vec![3, 4, 5]);
+        let subscription = state.subscribe_to_updates();
+
+        // Forks off block 1, which has left the history
+        let base = state.revert(&link(2, fork_hash(2), hash(1)), state.snapshot());
+        assert_eq!(base.block_number, 5);
+        assert_eq!(history_blocks(&state), vec![3, 4, 5]);
+        assert!(subscription.outbox().recv().now_or_never().is_none());
+    }
+
+    #[test]
+    fn applies_a_block_after_a_gap_on_top() {
+        let state = state(8);
+        apply_blocks(&state, 3);
+        let subscription = state.subscribe_to_updates();
+
+        // E.g. the first block after the stream reconnected
+        let base = state.revert(&link(9, hash(9), hash(8)), state.snapshot());
+        assert_eq!(base.block_number, 3);
+        assert_eq!(history_blocks(&state), vec![1, 2, 3]);
+        assert!(subscription.outbox().recv().now_or_never().is_none());
     }
 }
@@ -0,0 +1,414 @@
+use num_bigint::BigUint;
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tracing::debug;
+use tycho_simulation::{
+    protocol::models::ProtocolComponent,
+    tycho_core::{models::token::Token, simulation::protocol_sim::ProtocolSim},
+};
+
+use crate::errors::ApiError;
+
+use super::executor::CancelToken;
+use super::fees::state_fee;
+use super::quarantine::isolate;
+use super::state::BlockSnapshot;
+
+/// One hop of a simulation request. Tokens may be left out where they can be
+/// inferred: `token_in` from the previous hop, `token_out` for two-token pools.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HopRequest {
+    pub pool: String,
+    #[serde(default)]
+    pub token_in: Option<String>,
+    #[serde(default)]
+    pub token_out: Option<String>,
+}
+
+/// A hop with both tokens resolved against the pool
+#[derive(Debug, Clone)]
+pub struct ResolvedHop {
+    pub pool: String,
+    pub token_in: Token,
+    pub token_out: Token,
+}
+
+/// What to do when an amount exceeds what a pool can take
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Fail with the maximum sellable amount
+    #[default]
+    Enforce,
+    /// Sell only up to the limit and report the rest as unfilled
+    Cap,
+}
+
+/// Part of a hop's input left unsold because it exceeded the pool's limit
+#[derive(Debug, Clone, Serialize)]
+pub struct UnfilledRemainder {
+    pub hop: usize,
+    pub pool: String,
+    /// Token the remainder is in, the hop's `token_in`
+    pub token: String,
+    pub amount: String,
+}
+
+/// Raw result of running an amount through a sequence of pools
+#[derive(Debug)]
+pub struct RouteOutcome {
+    /// Input actually sold by the first hop, less than requested if it was capped
+    pub amount_in: BigUint,
+    pub amount_out: BigUint,
+    pub total_gas: BigUint,
+    pub hops: Vec<HopOutcome>,
+    /// State of every pool the route went through, after its swaps
+    pub new_states: HashMap<String, Arc<dyn ProtocolSim>>,
+    /// Input left over at hops capped to their pool's limit
+    pub unfilled: Vec<UnfilledRemainder>,
+}
+
+impl RouteOutcome {
+    /// Product of every hop's spot price before the swap, i.e. the rate an
+    /// infinitesimally small trade would get along this route
+    pub fn spot_price(&self) -> Option<f64> {
+        self.hops
+            .iter()
+            .map(|hop| hop.spot_price_before)
+            .product::<Option<f64>>()
+    }
+}
+
+/// What happened in a single hop, to show where a route loses value.
+/// Prices are in units of `token_out` per `token_in`.
+#[derive(Debug, Clone, Serialize)]
+pub struct HopOutcome {
+    pub pool: String,
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_in: String,
+    pub amount_out: String,
+    pub gas: String,
+    pub spot_price_before: Option<f64>,
+    pub effective_price: f64,
+    pub spot_price_after: Option<f64>,
+    /// How much worse the effective price is than the spot price before
+    pub price_impact_pct: Option<f64>,
+    /// How far the swap moved the pool's spot price
+    pub spot_price_change_pct: Option<f64>,
+    /// Fee charged by the pool, in `token_in` base units
+    pub fee_paid: Option<String>,
+}
+
+impl HopOutcome {
+    fn new(
+        step: &ResolvedHop,
+        pool: &dyn ProtocolSim,
+        new_state: &dyn ProtocolSim,
+        spot_price_before: Option<f64>,
+        amount_in: &BigUint,
+        amount_out: &BigUint,
+        gas: &BigUint,
+    ) -> Self {
+        let effective_price = to_units(amount_out, step.token_out.decimals as u32)
+            / to_units(amount_in, step.token_in.decimals as u32);
+        let spot_price_after =
+            isolate(|| new_state.spot_price(&step.token_in, &step.token_out)).ok();
+        let spot_price_change_pct = match (spot_price_before, spot_price_after) {
+            (Some(before), Some(after)) if before > 0.0 => Some((after - before) / before * 100.0),
+            _ => None,
+        };
+        // Not every protocol implements `fee`, so this is best effort
+        let fee_paid = state_fee(pool)
+            .and_then(|fee| amount_in.to_f64().map(|amount| amount * fee))
+            .and_then(BigUint::from_f64)
+            .map(|fee| fee.to_string());
+
+        HopOutcome {
+            pool: step.pool.clone(),
+            token_in: step.token_in.address.to_string(),
+            token_out: step.token_out.address.to_string(),
+            amount_in: amount_in.to_string(),
+            amount_out: amount_out.to_string(),
+            gas: gas.to_string(),
+            spot_price_before,
+            effective_price,
+            spot_price_after,
+            price_impact_pct: shortfall_pct(spot_price_before, effective_price),
+            spot_price_change_pct,
+            fee_paid,
+        }
+    }
+}
+
+pub fn same_address(token: &Token, address: &str) -> bool {
+    token.address.to_string().eq_ignore_ascii_case(address)
+}
+
+pub fn find_token<'a>(component: &'a ProtocolComponent, address: &str) -> Option<&'a Token> {
+    component.tokens.iter().find(|token| same_address(token, address))
+}
+
+fn hop_error(hop: usize, pool: &str, message: String) -> ApiError {
+    ApiError::InvalidHop {
+        hop,
+        pool: pool.to_string(),
+        message,
+    }
+}
+
+/// Resolve every hop's tokens and check that the path is connected, i.e.
+/// each hop sells exactly what the previous one bought
+pub fn resolve_path(
+    snapshot: &BlockSnapshot,
+    sell_token: Option<&str>,
+    hops: &[HopRequest],
+) -> Result<Vec<ResolvedHop>, ApiError> {
+    if hops.is_empty() {
+        return Err(ApiError::InvalidInput("Route must contain at least one pool".to_string()));
+    }
+
+    let mut resolved: Vec<ResolvedHop> = Vec::with_capacity(hops.len());
+    for (hop, request) in hops.iter().enumerate() {
+        let pool = request.pool.as_str();
+        if let Some(entry) = snapshot.quarantined.get(pool) {
+            return Err(ApiError::PoolUnavailable {
+                hop,
+                pool: pool.to_string(),
+                message: format!(
+                    "quarantined since block {}: {}",
+                    entry.since_block, entry.reason
+                ),
+            });
+        }
+        let component = match snapshot.get_pool_state(pool) {
+            (Some(component), Some(_)) => component,
+            _ => {
+                return Err(ApiError::NotFound(format!(
+                    "Hop {}: pool not found: {}",
+                    hop, pool
+                )))
+            }
+        };
+
+        // What this hop has to sell: the previous hop's output, or the sell token
+        let expected_in = match resolved.last() {
+            Some(previous) => Some(previous.token_out.address.to_string()),
+            None => sell_token.map(str::to_string),
+        };
+        let token_in_address = match (&request.token_in, &expected_in) {
+            (Some(given), Some(expected)) if !given.eq_ignore_ascii_case(expected) => {
+                return Err(hop_error(
+                    hop,
+                    pool,
+                    if hop == 0 {
+                        format!("token_in {} does not match sell_token {}", given, expected)
+                    } else {
+                        format!(
+                            "token_in {} does not match token_out {} of hop {}, the path is disconnected",
+                            given,
+                            expected,
+                            hop - 1
+                        )
+                    },
+                ));
+            }
+            (Some(given), _) => given.clone(),
+            (None, Some(expected)) => expected.clone(),
+            (None, None) => {
+                return Err(hop_error(hop, pool, "token_in is required".to_string()))
+            }
+        };
+        let token_in = find_token(component, &token_in_address)
+            .ok_or_else(|| {
+                hop_error(
+                    hop,
+                    pool,
+                    format!("token {} is not traded by this pool", token_in_address),
+                )
+            })?
+            .clone();
+
+        let token_out = match &request.token_out {
+            Some(address) => {
+                if same_address(&token_in, address) {
+                    return Err(hop_error(
+                        hop,
+                        pool,
+                        format!("cannot swap token {} for itself", address),
+                    ));
+                }
+                find_token(component, address)
+                    .ok_or_else(|| {
+                        hop_error(
+                            hop,
+                            pool,
+                            format!("token {} is not traded by this pool", address),
+                        )
+                    })?
+                    .clone()
+            }
+            None => {
+                // Two-token pools have only one choice, Curve or Balancer
+                // pools with more tokens need the caller to name it
+                let mut candidates = component
+                    .tokens
+                    .iter()
+                    .filter(|token| token.address != token_in.address);
+                match (candidates.next(), candidates.next()) {
+                    (Some(token_out), None) => token_out.clone(),
+                    _ => {
+                        return Err(hop_error(
+                            hop,
+                            pool,
+                            format!(
+                                "pool has {} tokens, token_out is required",
+                                component.tokens.len()
+                            ),
+                        ))
+                    }
+                }
+            }
+        };
+
+        resolved.push(ResolvedHop {
+            pool: pool.to_string(),
+            token_in,
+            token_out,
+        });
+    }
+    Ok(resolved)
+}
+
+/// Convert raw token units to a float amount of whole tokens
+pub fn to_units(amount: &BigUint, decimals: u32) -> f64 {
+    amount.to_f64().unwrap_or(f64::NAN) / 10f64.powi(decimals as i32)
+}
+
+/// Percentage by which `actual` falls short of `reference`
+fn shortfall_pct(reference: Option<f64>, actual: f64) -> Option<f64> {
+    reference
+        .filter(|reference| *reference > 0.0 && actual.is_finite())
+        .map(|reference| (reference - actual) / reference * 100.0)
+}
+
+/// Check an amount against the most the pool accepts of `token_in`. Pools
+/// that can't report limits are trusted to error on their own.
+fn check_limits(
+    hop: usize,
+    step: &ResolvedHop,
+    pool: &dyn ProtocolSim,
+    amount: BigUint,
+    mode: LimitMode,
+    unfilled: &mut Vec<UnfilledRemainder>,
+) -> Result<BigUint, ApiError> {
+    let max_sell = match isolate(|| {
+        pool.get_limits(step.token_in.address.clone(), step.token_out.address.clone())
+    }) {
+        Ok((max_sell, _)) => max_sell,
+        Err(e) => {
+            debug!("Hop {}: no limits for pool {}: {}", hop, step.pool, e);
+            return Ok(amount);
+        }
+    };
+    if amount <= max_sell {
+        return Ok(amount);
+    }
+    match mode {
+        LimitMode::Enforce => Err(ApiError::ExceedsLimit {
+            hop,
+            pool: step.pool.clone(),
+            token: step.token_in.address.to_string(),
+            requested: amount.to_string(),
+            max_sell: max_sell.to_string(),
+        }),
+        LimitMode::Cap => {
+            unfilled.push(UnfilledRemainder {
+                hop,
+                pool: step.pool.clone(),
+                token: step.token_in.address.to_string(),
+                amount: (&amount - &max_sell).to_string(),
+            });
+            Ok(max_sell)
+        }
+    }
+}
+
+/// Run the amount through every hop in order. A pool used more than once sees
+/// the state left by its earlier swap. Each hop is checked against its pool's
+/// limits first, see [`LimitMode`]. Meant to run on a blocking worker, and
+/// stops between hops once the request has been cancelled.
+pub fn simulate_path(
+    snapshot: &BlockSnapshot,
+    hops: &[ResolvedHop],
+    amount_in: BigUint,
+    limit_mode: LimitMode,
+    cancel: &CancelToken,
+) -> Result<RouteOutcome, ApiError> {
+    let mut current_amount = amount_in;
+    let mut filled_amount_in = None;
+    let mut unfilled = Vec::new();
+    let mut total_gas = BigUint::from(0u64);
+    let mut breakdown = Vec::with_capacity(hops.len());
+    let mut new_states: HashMap<String, Arc<dyn ProtocolSim>> = HashMap::new();
+
+    for (hop, step) in hops.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(ApiError::Timeout(format!(
+                "Simulation abandoned before hop {} (pool {})",
+                hop, step.pool
+            )));
+        }
+        let Some(pool) = new_states
+            .get(&step.pool)
+            .or_else(|| snapshot.states.get(&step.pool))
+            .cloned()
+        else {
+            return Err(ApiError::NotFound(format!(
+                "Hop {}: pool not found: {}",
+                hop, step.pool
+            )));
+        };
+
+        current_amount = check_limits(hop, step, pool.as_ref(), current_amount, limit_mode, &mut unfilled)?;
+        filled_amount_in.get_or_insert_with(|| current_amount.clone());
+
+        let spot_price_before = isolate(|| pool.spot_price(&step.token_in, &step.token_out)).ok();
+        let amount_in = current_amount.clone();
+        let result = isolate(|| pool.get_amount_out(current_amount, &step.token_in, &step.token_out))
+            .map_err(|e| ApiError::SimulationError(format!("Hop {}: simulation error: {}", hop, e)))?;
+
+        debug!(
+            "Hop {} through {}: {} {} -> {} {}, gas {}",
+            hop,
+            step.pool,
+            amount_in,
+            step.token_in.address,
+            result.amount,
+            step.token_out.address,
+            result.gas
+        );
+
+        breakdown.push(HopOutcome::new(
+            step,
+            pool.as_ref(),
+            result.new_state.as_ref(),
+            spot_price_before,
+            &amount_in,
+            &result.amount,
+            &result.gas,
+        ));
+        current_amount = result.amount;
+        total_gas += result.gas;
+        new_states.insert(step.pool.clone(), Arc::from(result.new_state));
+    }
+
+    Ok(RouteOutcome {
+        amount_in: filled_amount_in.unwrap_or_default(),
+        amount_out: current_amount,
+        total_gas,
+        hops: breakdown,
+        new_states,
+        unfilled,
+    })
+}
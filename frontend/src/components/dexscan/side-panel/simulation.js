@@ -14,11 +14,13 @@ const getApiUrl = (chain) => {
 };
 
 // Private: Call API
-const callAPI = async (tokenIn, poolId, amount, chain) => {
+const callAPI = async (tokenIn, tokenOut, poolId, amount, chain) => {
   const apiUrl = getApiUrl(chain);
   const requestBody = {
     sell_token: tokenIn,
     pools: [poolId],
+    // Required by the API for pools with more than two tokens
+    buy_tokens: [tokenOut],
     amount: amount.toString()  // Ensure amount is sent as string
   };
   
@@ -59,7 +61,7 @@ export function createSimulation(pool, chain) {
       const buyTokenData = pool.tokens.find(t => t.address === buyToken);
       
       // Call API - pass amount as-is, callAPI will convert to string
-      const result = await callAPI(sellToken, pool.id, amount, chain);
+      const result = await callAPI(sellToken, buyToken, pool.id, amount, chain);
       
       // Calculate results - API now returns strings
       const outputAmount = parseFloat(result.output_amount);